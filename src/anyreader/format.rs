@@ -8,6 +8,8 @@ use tracing::trace;
 /// A reader that contains a detected file format.
 pub struct AnyFormat<T: Read> {
     pub kind: FormatKind,
+    /// The MIME type of the (decompressed) content, if it could be detected.
+    pub mime_type: Option<&'static str>,
     reader: Peekable<AnyReader<T>>,
 }
 
@@ -22,6 +24,7 @@ impl<T: Read> AnyFormat<T> {
         reader.fill_peek_buf().ok();
         let buf = crate::anyreader::peek_upto::<MAX_PEEK_BUFFER_SIZE>(&mut reader);
         trace!("peeked {} bytes", buf.len());
+        let mime_type = infer::get(buf).map(|t| t.mime_type());
 
         let format: FormatKind = if infer::archive::is_tar(buf) {
             FormatKind::Tar
@@ -43,14 +46,20 @@ impl<T: Read> AnyFormat<T> {
             format
         };
 
-        trace!("format detected: {format:?}");
+        trace!("format detected: {format:?}, mime type: {mime_type:?}");
 
         Ok(AnyFormat {
             kind: format,
+            mime_type,
             reader,
         })
     }
 
+    /// Returns true if the content is decompressed as it is read
+    pub fn is_compressed(&self) -> bool {
+        !self.reader.get_ref().1.is_unknown()
+    }
+
     pub fn get_ref(&self) -> &T {
         self.reader.get_ref().1.get_ref()
     }
@@ -60,6 +69,7 @@ impl<T: Read> Debug for AnyFormat<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AnyFormat")
             .field("kind", &self.kind)
+            .field("mime_type", &self.mime_type)
             .finish()
     }
 }
@@ -98,6 +98,12 @@ impl<T: Read> FileEntry<T> {
         self.details.size
     }
 
+    /// The size of the content that will be read, if it is known before reading it. The size of
+    /// a compressed entry is the compressed size, so is not the size of the content.
+    pub fn content_size(&self) -> Option<u64> {
+        (self.details.size > 0 && !self.inner.is_compressed()).then_some(self.details.size)
+    }
+
     pub fn supports_recursion(&self) -> bool {
         matches!(self.inner.kind, FormatKind::Tar | FormatKind::Zip)
     }
@@ -106,6 +112,10 @@ impl<T: Read> FileEntry<T> {
         self.inner.kind
     }
 
+    pub fn mime_type(&self) -> Option<&'static str> {
+        self.inner.mime_type
+    }
+
     pub fn get_ref(&self) -> &T {
         self.inner.get_ref()
     }
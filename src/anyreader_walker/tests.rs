@@ -1,5 +1,5 @@
 use crate::anyreader::FormatKind;
-use crate::anyreader::test::{assert_data_equal_with_msg, read_vec};
+use crate::anyreader::test::{assert_data_equal_with_msg, gzip_data, read_vec};
 use crate::anyreader_walker::EntryDetails;
 use crate::anyreader_walker::entry::FileEntry;
 use crate::anyreader_walker::stack::AnyWalker;
@@ -48,3 +48,14 @@ pub fn assert_visitor_equal(
         assert_data_equal_with_msg(e_data, f_data, format!("Index {idx}"));
     }
 }
+
+#[test]
+fn test_content_size() {
+    let entry = FileEntry::from_bytes("test", TEST_DATA).unwrap();
+    assert_eq!(entry.content_size(), Some(TEST_DATA.len() as u64));
+    // The size of a compressed entry is not the size of its content
+    let entry = FileEntry::from_bytes("test.gz", gzip_data(TEST_DATA)).unwrap();
+    assert_eq!(entry.content_size(), None);
+    let entry = FileEntry::from_reader("unknown", 0, TEST_DATA).unwrap();
+    assert_eq!(entry.content_size(), None);
+}
@@ -1,42 +1,86 @@
 use crate::anyreader_walker::FileEntry;
+use crate::classify::{detect_encoding, transcode};
+use crate::dedupe::SeenHashes;
+use crate::grep::{ContentMatcher, MatchingWriter};
 use crate::hasher::{HASH_SIZE, HashedWriter};
-use crate::{ConvertionOptions, FormatKind, IncludeType};
+use crate::incremental::SourceFingerprint;
+use crate::predicate::ContentMetadata;
+use crate::secrets::{
+    ScanningWriter, SecretScanner, append_findings, findings_builder, findings_field,
+};
+use crate::{ConvertionOptions, FormatKind, IncludeType, PartitionBy};
 use arrow::array::{
-    Array, ArrayBuilder, AsArray, BooleanArray, FixedSizeBinaryBuilder, LargeBinaryBuilder,
-    PrimitiveBuilder, StringViewBuilder,
+    Array, ArrayBuilder, ArrayRef, AsArray, BooleanArray, BooleanBufferBuilder, BooleanBuilder,
+    FixedSizeBinaryBuilder, LargeBinaryBuilder, LargeStringBuilder, ListBuilder, PrimitiveBuilder,
+    StringBuilder, StringViewBuilder, StructBuilder,
 };
 use arrow::compute::filter_record_batch;
-use arrow::datatypes::{DataType, Field, Schema, SchemaRef, UInt64Type};
+use arrow::datatypes::{
+    DataType, Field, Schema, SchemaRef, TimeUnit, TimestampMillisecondType, UInt64Type,
+};
 use arrow::error::ArrowError;
 use arrow::record_batch::RecordBatch;
 use byte_unit::Byte;
 use extract_strings::AsciiStrings;
+use ring::digest::Digest;
 use std::fmt::{Display, Formatter};
 use std::io::{Read, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, LazyLock};
+use std::sync::Arc;
 use tracing::{debug, trace};
 
-static ARROW_SCHEMA: LazyLock<Arc<Schema>> = LazyLock::new(|| {
-    let schema = Schema::new([
-        Arc::new(Field::new("source", DataType::Utf8View, false)),
-        Arc::new(Field::new("path", DataType::Utf8View, false)),
-        Arc::new(Field::new("size", DataType::UInt64, false)),
-        Arc::new(Field::new(
-            "hash",
-            DataType::FixedSizeBinary(HASH_SIZE as i32),
-            false,
-        )),
-        Arc::new(Field::new("content", DataType::LargeBinary, false)),
+pub fn arrow_schema(options: &ConvertionOptions) -> SchemaRef {
+    let mut fields = vec![Field::new("source", DataType::Utf8View, false)];
+    if options.incremental {
+        fields.push(Field::new("source_size", DataType::UInt64, false));
+        fields.push(Field::new(
+            "source_mtime",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("+00:00".into())),
+            true,
+        ));
+    }
+    fields.extend([
+        Field::new("path", DataType::Utf8View, false),
+        Field::new("size", DataType::UInt64, false),
+        Field::new("hash", DataType::FixedSizeBinary(HASH_SIZE as i32), false),
     ]);
-    Arc::new(schema)
-});
-
-pub fn arrow_schema() -> Arc<Schema> {
-    (*ARROW_SCHEMA).clone()
+    if options.partition_by == Some(PartitionBy::Format) {
+        fields.push(Field::new("format", DataType::Utf8View, false));
+    }
+    fields.push(Field::new("mime_type", DataType::Utf8View, true));
+    if options.classify_content {
+        fields.push(Field::new("is_text", DataType::Boolean, false));
+        fields.push(Field::new("encoding", DataType::Utf8View, true));
+    }
+    if options.scan_secrets {
+        fields.push(findings_field());
+    }
+    if options.matched_patterns_column {
+        fields.push(Field::new(
+            "matched_patterns",
+            DataType::List(Arc::new(Field::new_list_field(DataType::Utf8, true))),
+            false,
+        ));
+    }
+    if !options.no_content {
+        if options.max_content_size.is_some() {
+            fields.push(Field::new("content_truncated", DataType::Boolean, false));
+            fields.push(Field::new("original_size", DataType::UInt64, false));
+        }
+        if !options.no_raw_content {
+            fields.push(Field::new("content", DataType::LargeBinary, false));
+        }
+        if options.text_column {
+            fields.push(Field::new("text", DataType::LargeUtf8, true));
+        }
+    }
+    Arc::new(Schema::new(fields))
 }
 
+/// The largest content buffer kept between entries when skipping duplicates
+const MAX_RETAINED_SCRATCH: usize = 16 * 1024 * 1024;
+
 #[inline(always)]
 fn infallable_copy(reader: &mut impl Read, writer: &mut impl Write) -> u64 {
     const BUFFER_SIZE: usize = 1024 * 8; // 8KB
@@ -58,15 +102,75 @@ fn infallable_copy(reader: &mut impl Read, writer: &mut impl Write) -> u64 {
     total_bytes
 }
 
+/// A writer that only stores the first `limit` bytes written to it. Any bytes past the limit are
+/// discarded, but are still reported as written so that the full stream is hashed and counted.
+struct TruncatingWriter<W: Write> {
+    inner: W,
+    limit: Option<u64>,
+    stored: u64,
+}
+
+impl<W: Write> TruncatingWriter<W> {
+    fn new(inner: W, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            limit,
+            stored: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for TruncatingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let to_store = match self.limit {
+            None => buf.len(),
+            Some(limit) => (limit.saturating_sub(self.stored) as usize).min(buf.len()),
+        };
+        if to_store > 0 {
+            self.inner.write_all(&buf[..to_store])?;
+            self.stored += to_store as u64;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 #[derive(Debug)]
 pub struct OutputBatch {
     capacity: usize,
     schema: SchemaRef,
     sources: StringViewBuilder,
+    source_fingerprint: SourceFingerprint,
+    source_sizes: Option<PrimitiveBuilder<UInt64Type>>,
+    source_mtimes: Option<PrimitiveBuilder<TimestampMillisecondType>>,
     paths: StringViewBuilder,
     sizes: PrimitiveBuilder<UInt64Type>,
-    content: LargeBinaryBuilder,
+    /// Only written when partitioning by format
+    formats: Option<StringViewBuilder>,
+    mime_types: StringViewBuilder,
+    is_text: Option<BooleanBuilder>,
+    encodings: Option<StringViewBuilder>,
+    content: Option<LargeBinaryBuilder>,
+    /// The content transcoded to UTF-8, or null if it is binary
+    text: Option<LargeStringBuilder>,
+    content_truncated: Option<BooleanBuilder>,
+    original_sizes: Option<PrimitiveBuilder<UInt64Type>>,
     hashes: FixedSizeBinaryBuilder,
+    /// Set when duplicate entries are skipped as they are read
+    seen_hashes: Option<SeenHashes>,
+    /// Content is read into this buffer until it is known to be unique
+    scratch: Vec<u8>,
+    /// Which rows matched the predicate and content patterns, if there are any
+    selected: Option<BooleanBufferBuilder>,
+    /// Searches the content of each entry, if there are content patterns
+    matcher: Option<ContentMatcher>,
+    matched_patterns: Option<ListBuilder<StringBuilder>>,
+    /// Scans the content of each entry for secrets, if enabled
+    scanner: Option<SecretScanner>,
+    findings: Option<ListBuilder<StructBuilder>>,
     options: ConvertionOptions,
     extract_strings: bool,
     // target_content_size: Byte,
@@ -78,18 +182,62 @@ impl OutputBatch {
         let capacity = 1024;
         Self {
             capacity,
-            schema: arrow_schema(),
+            schema: arrow_schema(&options),
             sources: StringViewBuilder::with_capacity(capacity).with_deduplicate_strings(),
+            source_fingerprint: SourceFingerprint::default(),
+            source_sizes: options
+                .incremental
+                .then(|| PrimitiveBuilder::with_capacity(capacity)),
+            source_mtimes: options
+                .incremental
+                .then(|| PrimitiveBuilder::with_capacity(capacity).with_timezone("+00:00")),
             paths: StringViewBuilder::with_capacity(capacity),
             sizes: PrimitiveBuilder::with_capacity(capacity),
-            content: LargeBinaryBuilder::with_capacity(capacity, capacity * 1024),
+            formats: (options.partition_by == Some(PartitionBy::Format))
+                .then(|| StringViewBuilder::with_capacity(capacity).with_deduplicate_strings()),
+            mime_types: StringViewBuilder::with_capacity(capacity).with_deduplicate_strings(),
+            is_text: options
+                .classify_content
+                .then(|| BooleanBuilder::with_capacity(capacity)),
+            encodings: options
+                .classify_content
+                .then(|| StringViewBuilder::with_capacity(capacity).with_deduplicate_strings()),
+            content: (!options.no_content && !options.no_raw_content)
+                .then(|| LargeBinaryBuilder::with_capacity(capacity, capacity * 1024)),
+            text: (!options.no_content && options.text_column)
+                .then(|| LargeStringBuilder::with_capacity(capacity, capacity * 1024)),
+            content_truncated: (!options.no_content && options.max_content_size.is_some())
+                .then(|| BooleanBuilder::with_capacity(capacity)),
+            original_sizes: (!options.no_content && options.max_content_size.is_some())
+                .then(|| PrimitiveBuilder::with_capacity(capacity)),
             hashes: FixedSizeBinaryBuilder::with_capacity(capacity, HASH_SIZE as i32),
+            seen_hashes: None,
+            scratch: vec![],
+            selected: (options.predicate.is_some() || !options.content_patterns.is_empty())
+                .then(|| BooleanBufferBuilder::new(capacity)),
+            matcher: options.content_patterns.matcher(),
+            matched_patterns: options
+                .matched_patterns_column
+                .then(|| ListBuilder::with_capacity(StringBuilder::new(), capacity)),
+            scanner: options.scan_secrets.then(SecretScanner::default),
+            findings: options.scan_secrets.then(|| findings_builder(capacity)),
             total_content_size: 0u64.into(),
             extract_strings: options.extract_strings,
             options,
         }
     }
 
+    /// Set the fingerprint of the input that records are being added from
+    pub fn with_source_fingerprint(mut self, fingerprint: SourceFingerprint) -> Self {
+        self.source_fingerprint = fingerprint;
+        self
+    }
+
+    /// Skip entries with content that has already been seen, by this or any other batch
+    pub(crate) fn set_seen_hashes(&mut self, seen_hashes: SeenHashes) {
+        self.seen_hashes = Some(seen_hashes);
+    }
+
     pub fn is_empty(&self) -> bool {
         self.sources.is_empty()
     }
@@ -98,54 +246,251 @@ impl OutputBatch {
         self.sources.len() >= self.capacity || self.total_content_size >= self.options.batch_size
     }
 
+    /// Read an entry and add it to the batch, returning the number of bytes read. If the batch
+    /// has [SeenHashes], an entry with content that has already been seen is not added.
     pub fn add_record(
+        &mut self,
+        input_path: &Path,
+        source: PathBuf,
+        entry: &mut FileEntry<impl Read>,
+    ) -> std::io::Result<u64> {
+        self.add_filtered_record(input_path, source, entry, |_| true)
+    }
+
+    /// Read an entry and add it to the batch if `filter` returns true for its content and it
+    /// matches any of the content patterns. Entries that are filtered out are removed when the
+    /// record batch is created.
+    pub(crate) fn add_filtered_record(
         &mut self,
         input_path: &Path,
         mut source: PathBuf,
         entry: &mut FileEntry<impl Read>,
-    ) -> u64 {
+        filter: impl FnOnce(&ContentMetadata) -> bool,
+    ) -> std::io::Result<u64> {
         trace!(path=?entry.path(), size=?entry.size(), "add_record");
+        let limit = self.options.max_content_size.map(|b| b.as_u64());
+        // The content is read into the scratch buffer when it is only copied into the batch once it
+        // is known to be unique, or when only its text is stored
+        let into_scratch = match &self.content {
+            Some(_) => self.seen_hashes.is_some(),
+            None => self.text.is_some(),
+        };
+        let (digest, bytes_written, stored) = match &mut self.content {
+            _ if into_scratch => {
+                self.scratch.clear();
+                self.scratch.shrink_to(MAX_RETAINED_SCRATCH);
+                let mut writer = TruncatingWriter::new(&mut self.scratch, limit);
+                let (digest, bytes_written) = Self::copy_hashed(
+                    entry,
+                    ScanningWriter::new(
+                        MatchingWriter::new(&mut writer, self.matcher.as_mut()),
+                        self.scanner.as_mut(),
+                    ),
+                    self.extract_strings,
+                );
+                (digest, bytes_written, writer.stored)
+            }
+            Some(content) => {
+                let mut writer = TruncatingWriter::new(&mut *content, limit);
+                let (digest, bytes_written) = Self::copy_hashed(
+                    entry,
+                    ScanningWriter::new(
+                        MatchingWriter::new(&mut writer, self.matcher.as_mut()),
+                        self.scanner.as_mut(),
+                    ),
+                    self.extract_strings,
+                );
+                let stored = writer.stored;
+                // Finish the value that was copied into the buffer by appending an empty value.
+                content.append_value("");
+                (digest, bytes_written, stored)
+            }
+            None => {
+                let (digest, bytes_written) = Self::copy_hashed(
+                    entry,
+                    ScanningWriter::new(
+                        MatchingWriter::new(std::io::sink(), self.matcher.as_mut()),
+                        self.scanner.as_mut(),
+                    ),
+                    self.extract_strings,
+                );
+                (digest, bytes_written, bytes_written)
+            }
+        };
+        let stored_content = match &self.content {
+            _ if into_scratch => Some(self.scratch.as_slice()),
+            Some(content) => {
+                let offsets = content.offsets_slice();
+                Some(&content.values_slice()[offsets[offsets.len() - 2] as usize..])
+            }
+            None => None,
+        };
+        let truncated = stored < bytes_written;
+        let encoding = match stored_content {
+            Some(content) if self.encodings.is_some() || self.text.is_some() => {
+                Some(detect_encoding(content, truncated))
+            }
+            _ => None,
+        };
+        let matched = self.matcher.as_mut().map(|matcher| matcher.finish());
+        let findings = self.scanner.as_mut().map(|scanner| scanner.finish());
+        let selected = matched.as_ref().is_none_or(|matched| !matched.is_empty())
+            && filter(&ContentMetadata::new(
+                bytes_written,
+                stored_content,
+                truncated,
+            ));
+        // Content that was copied straight into the batch is removed once the batch is created
+        let appended = self.content.is_some() && !into_scratch;
+        if !selected && !appended {
+            trace!(path=?entry.path(), bytes_written=bytes_written, "record_filtered");
+            return Ok(bytes_written);
+        }
+        if let Some(seen_hashes) = &self.seen_hashes
+            && !seen_hashes.insert(digest.as_ref().try_into().expect("hash size"))?
+        {
+            trace!(path=?entry.path(), bytes_written=bytes_written, "duplicate_skipped");
+            return Ok(bytes_written);
+        }
+
         self.sources.append_value(input_path.to_string_lossy());
+        if let Some(source_sizes) = &mut self.source_sizes {
+            source_sizes.append_value(self.source_fingerprint.size);
+        }
+        if let Some(source_mtimes) = &mut self.source_mtimes {
+            source_mtimes.append_option(self.source_fingerprint.modified);
+        }
 
         source.push(entry.path());
         self.paths.append_value(source.to_string_lossy());
+        if let Some(formats) = &mut self.formats {
+            formats.append_value(entry.format().to_string());
+        }
+        self.mime_types.append_option(entry.mime_type());
+        if let (Some(is_text), Some(encodings), Some(encoding)) =
+            (&mut self.is_text, &mut self.encodings, encoding)
+        {
+            is_text.append_value(encoding.is_some());
+            encodings.append_option(encoding.map(|encoding| encoding.name()));
+        }
+        if let (Some(builder), Some(findings)) = (&mut self.findings, &findings) {
+            append_findings(builder, findings);
+        }
+        if let Some(matched_patterns) = &mut self.matched_patterns {
+            let patterns = self.options.content_patterns.patterns();
+            for idx in matched.iter().flatten() {
+                matched_patterns.values().append_value(&patterns[*idx]);
+            }
+            matched_patterns.append(true);
+        }
+        self.sizes.append_value(stored);
+        if let Some(text) = &mut self.text {
+            let content = stored_content.expect("text requires the content");
+            // Rows that are not selected are removed, so are not transcoded
+            match encoding.flatten().filter(|_| selected) {
+                Some(encoding) => {
+                    let transcoded = transcode(
+                        content,
+                        encoding,
+                        self.options.strip_bom,
+                        self.options.normalize_line_endings,
+                    );
+                    self.total_content_size =
+                        (self.total_content_size.as_u64() + transcoded.len() as u64).into();
+                    text.append_value(transcoded);
+                }
+                None => text.append_null(),
+            }
+        }
+        if let Some(content) = &mut self.content {
+            if into_scratch {
+                content.append_value(&self.scratch);
+            }
+            self.total_content_size = (self.total_content_size.as_u64() + stored).into();
+        }
+        if let Some(original_sizes) = &mut self.original_sizes {
+            original_sizes.append_value(bytes_written);
+        }
+        if let Some(content_truncated) = &mut self.content_truncated {
+            content_truncated.append_value(truncated);
+        }
+        self.hashes
+            .append_value(digest.as_ref())
+            .expect("Error appending hash");
+        if let Some(selected_rows) = &mut self.selected {
+            selected_rows.append(selected);
+        }
+        trace!(path=?entry.path(), bytes_written=bytes_written, "record_added");
+        Ok(bytes_written)
+    }
 
-        let mut hashed_writer = HashedWriter::new(&mut self.content);
-        if self.extract_strings && entry.format() == FormatKind::Executable {
+    fn copy_hashed(
+        entry: &mut FileEntry<impl Read>,
+        writer: impl Write,
+        extract_strings: bool,
+    ) -> (Digest, u64) {
+        let mut hashed_writer = HashedWriter::new(writer);
+        if extract_strings && entry.format() == FormatKind::Executable {
             for string in entry.iter_ascii_strings(10) {
                 writeln!(hashed_writer, "{}", string).unwrap();
             }
         } else {
-            // Copy the data into the buffer, and finish it with appending an empty value.
             infallable_copy(entry, &mut hashed_writer);
         };
-        let (digest, bytes_written) = hashed_writer.into_inner();
-        self.content.append_value("");
-        self.hashes
-            .append_value(digest.as_ref())
-            .expect("Error appending hash");
-        self.sizes.append_value(bytes_written);
-        self.total_content_size = (self.total_content_size.as_u64() + bytes_written).into();
-        trace!(path=?entry.path(), bytes_written=bytes_written, "record_added");
-        bytes_written
+        hashed_writer.into_inner()
     }
 
     pub fn create_record_batch_and_reset(&mut self) -> Result<RecordBatch, ArrowError> {
         debug!(total_content_size=?self.total_content_size, "create_record_batch_and_reset");
         self.total_content_size = 0u64.into();
-        let batch = RecordBatch::try_new(
-            self.schema.clone(),
-            vec![
-                Arc::new(self.sources.finish()),
-                Arc::new(self.paths.finish()),
-                Arc::new(self.sizes.finish()),
-                Arc::new(self.hashes.finish()),
-                Arc::new(self.content.finish()),
-            ],
-        )?;
-        let batch = match self.options.include {
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(self.sources.finish())];
+        if let Some(source_sizes) = &mut self.source_sizes {
+            columns.push(Arc::new(source_sizes.finish()));
+        }
+        if let Some(source_mtimes) = &mut self.source_mtimes {
+            columns.push(Arc::new(source_mtimes.finish()));
+        }
+        columns.extend([
+            Arc::new(self.paths.finish()) as ArrayRef,
+            Arc::new(self.sizes.finish()),
+            Arc::new(self.hashes.finish()),
+        ]);
+        if let Some(formats) = &mut self.formats {
+            columns.push(Arc::new(formats.finish()));
+        }
+        columns.push(Arc::new(self.mime_types.finish()));
+        if let (Some(is_text), Some(encodings)) = (&mut self.is_text, &mut self.encodings) {
+            columns.push(Arc::new(is_text.finish()));
+            columns.push(Arc::new(encodings.finish()));
+        }
+        if let Some(findings) = &mut self.findings {
+            columns.push(Arc::new(findings.finish()));
+        }
+        if let Some(matched_patterns) = &mut self.matched_patterns {
+            columns.push(Arc::new(matched_patterns.finish()));
+        }
+        if let Some(content_truncated) = &mut self.content_truncated {
+            columns.push(Arc::new(content_truncated.finish()));
+        }
+        if let Some(original_sizes) = &mut self.original_sizes {
+            columns.push(Arc::new(original_sizes.finish()));
+        }
+        if let Some(content) = &mut self.content {
+            columns.push(Arc::new(content.finish()));
+        }
+        if let Some(text) = &mut self.text {
+            columns.push(Arc::new(text.finish()));
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        let batch = match &mut self.selected {
+            Some(selected) => {
+                filter_record_batch(&batch, &BooleanArray::new(selected.finish(), None))?
+            }
+            None => batch,
+        };
+        let batch = match &self.options.include {
             IncludeType::All => batch,
-            _ => Self::filter_types(self.options.include, batch)?,
+            include => Self::filter_types(include, batch)?,
         };
         let batch = match &self.options.get_size_range() {
             None => batch,
@@ -154,27 +499,61 @@ impl OutputBatch {
         Ok(batch)
     }
 
-    #[inline(always)]
-    fn is_utf8(v: &[u8]) -> bool {
-        simdutf8::basic::from_utf8(v).is_ok()
-    }
-
     fn filter_types(
-        include: IncludeType,
+        include: &IncludeType,
         batch: RecordBatch,
     ) -> parquet::errors::Result<RecordBatch> {
-        let column = batch.column_by_name("content").unwrap().as_binary::<i64>();
-        assert!(!column.is_nullable(), "Content column is nullable");
+        let has_content = ["content", "is_text", "text"]
+            .iter()
+            .any(|name| batch.column_by_name(name).is_some());
+        if !has_content && !include.is_mime() {
+            return Err(ArrowError::InvalidArgumentError(format!(
+                "Including {include} files requires the content column"
+            ))
+            .into());
+        }
         let filter_array = match include {
             IncludeType::All => return Ok(batch),
-            IncludeType::Text => BooleanArray::from_iter(
-                column.iter().map(|path| Some(Self::is_utf8(path.unwrap()))),
-            ),
-            IncludeType::Binary => BooleanArray::from_iter(
-                column
-                    .iter()
-                    .map(|path| Some(!Self::is_utf8(path.unwrap()))),
-            ),
+            IncludeType::Mime(_) => {
+                let mime_types = batch.column_by_name("mime_type").unwrap().as_string_view();
+                BooleanArray::from_iter(
+                    mime_types
+                        .iter()
+                        .map(|mime_type| Some(include.matches_mime(mime_type))),
+                )
+            }
+            IncludeType::Text | IncludeType::Binary
+                if let Some(is_text) = batch.column_by_name("is_text") =>
+            {
+                let include_text = include == &IncludeType::Text;
+                BooleanArray::from_iter(
+                    is_text
+                        .as_boolean()
+                        .iter()
+                        .map(|is_text| Some(is_text == Some(include_text))),
+                )
+            }
+            IncludeType::Text | IncludeType::Binary
+                if let Some(text) = batch.column_by_name("text") =>
+            {
+                // Only text content is transcoded, so binary content has a null text value
+                let include_text = include == &IncludeType::Text;
+                BooleanArray::from_iter(
+                    (0..text.len()).map(|idx| Some(text.is_valid(idx) == include_text)),
+                )
+            }
+            IncludeType::Text | IncludeType::Binary => {
+                let column = batch.column_by_name("content").unwrap().as_binary::<i64>();
+                assert!(!column.is_nullable(), "Content column is nullable");
+                let include_text = include == &IncludeType::Text;
+                let truncated = batch
+                    .column_by_name("content_truncated")
+                    .map(|c| c.as_boolean().clone());
+                BooleanArray::from_iter(column.iter().enumerate().map(|(idx, content)| {
+                    let truncated = truncated.as_ref().is_some_and(|t| t.value(idx));
+                    Some(detect_encoding(content.unwrap(), truncated).is_some() == include_text)
+                }))
+            }
         };
         Ok(filter_record_batch(&batch, &filter_array)?)
     }
@@ -183,8 +562,10 @@ impl OutputBatch {
         size_range: &Range<Byte>,
         batch: RecordBatch,
     ) -> parquet::errors::Result<RecordBatch> {
+        // Filter on the original size of the content, if it was truncated.
         let sizes = batch
-            .column_by_name("size")
+            .column_by_name("original_size")
+            .or_else(|| batch.column_by_name("size"))
             .unwrap()
             .as_primitive::<UInt64Type>();
         assert!(!sizes.is_nullable(), "Size column is nullable");
@@ -206,3 +587,147 @@ impl Display for OutputBatch {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ConvertionOptions;
+    use crate::anyreader_walker::FileEntry;
+    use crate::batch::OutputBatch;
+    use crate::dedupe::SeenHashes;
+    use arrow::array::{Array, AsArray, RecordBatch};
+    use arrow::datatypes::UInt64Type;
+    use byte_unit::Byte;
+    use ring::digest::{SHA256, digest};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_truncated_content() {
+        let mut options = ConvertionOptions::const_default();
+        options.max_content_size = Some(Byte::from(5u64));
+        let mut batch = OutputBatch::new_with_options(options);
+        let mut entry = FileEntry::from_bytes("test", b"hello world".as_slice()).unwrap();
+        let written = batch
+            .add_record(Path::new("input"), PathBuf::new(), &mut entry)
+            .unwrap();
+        assert_eq!(written, 11);
+
+        let batch = batch.create_record_batch_and_reset().unwrap();
+        let column = |name| batch.column_by_name(name).unwrap();
+        assert_eq!(column("content").as_binary::<i64>().value(0), b"hello");
+        assert_eq!(column("size").as_primitive::<UInt64Type>().value(0), 5);
+        assert_eq!(
+            column("original_size")
+                .as_primitive::<UInt64Type>()
+                .value(0),
+            11
+        );
+        assert!(column("content_truncated").as_boolean().value(0));
+        assert_eq!(
+            column("hash").as_fixed_size_binary().value(0),
+            digest(&SHA256, b"hello world").as_ref()
+        );
+    }
+
+    #[test]
+    fn test_no_content() {
+        let mut options = ConvertionOptions::const_default();
+        options.no_content = true;
+        let mut batch = OutputBatch::new_with_options(options);
+        // Larger than the copy buffer, so the content is read in several parts
+        let content: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        let mut entry = FileEntry::from_bytes("test", content.clone()).unwrap();
+        let written = batch
+            .add_record(Path::new("input"), PathBuf::new(), &mut entry)
+            .unwrap();
+        assert_eq!(written, 20_000);
+
+        let batch = batch.create_record_batch_and_reset().unwrap();
+        assert!(batch.column_by_name("content").is_none());
+        let column = |name| batch.column_by_name(name).unwrap();
+        assert_eq!(column("size").as_primitive::<UInt64Type>().value(0), 20_000);
+        assert_eq!(
+            column("hash").as_fixed_size_binary().value(0),
+            digest(&SHA256, &content).as_ref()
+        );
+    }
+
+    #[test]
+    fn test_skip_duplicates() {
+        let mut options = ConvertionOptions::const_default();
+        options.unique = true;
+        let seen_hashes = SeenHashes::new(&options).unwrap();
+        let mut first = OutputBatch::new_with_options(options.clone());
+        let mut second = OutputBatch::new_with_options(options);
+        first.set_seen_hashes(seen_hashes.clone());
+        second.set_seen_hashes(seen_hashes.clone());
+
+        fn add(batch: &mut OutputBatch, content: &'static [u8]) {
+            let mut entry = FileEntry::from_bytes("test", content).unwrap();
+            let read = batch
+                .add_record(Path::new("input"), PathBuf::new(), &mut entry)
+                .unwrap();
+            assert_eq!(read, content.len() as u64);
+        }
+        add(&mut first, b"hello");
+        add(&mut first, b"world");
+        add(&mut second, b"hello");
+        add(&mut second, b"again");
+        assert_eq!(seen_hashes.len(), 3);
+
+        let first = first.create_record_batch_and_reset().unwrap();
+        let second = second.create_record_batch_and_reset().unwrap();
+        let content = |batch: &RecordBatch| -> Vec<Vec<u8>> {
+            let column = batch.column_by_name("content").unwrap().as_binary::<i64>();
+            column.iter().map(|c| c.unwrap().to_vec()).collect()
+        };
+        assert_eq!(content(&first), vec![b"hello".to_vec(), b"world".to_vec()]);
+        assert_eq!(content(&second), vec![b"again".to_vec()]);
+        assert_eq!(second.num_rows(), 1);
+    }
+
+    #[test]
+    fn test_text_column() {
+        let text = "\u{feff}one\r\ntwo\rthree".as_bytes();
+        let binary: Vec<u8> = (0u8..32).cycle().take(1024).collect();
+        let add = |batch: &mut OutputBatch, content: &[u8]| {
+            let mut entry = FileEntry::from_bytes("test", content.to_vec()).unwrap();
+            batch
+                .add_record(Path::new("input"), PathBuf::new(), &mut entry)
+                .unwrap();
+        };
+
+        let mut options = ConvertionOptions::const_default();
+        options.text_column = true;
+        let mut batch = OutputBatch::new_with_options(options.clone());
+        add(&mut batch, text);
+        add(&mut batch, &binary);
+        let batch = batch.create_record_batch_and_reset().unwrap();
+        let column = batch.column_by_name("text").unwrap().as_string::<i64>();
+        assert_eq!(column.value(0), "\u{feff}one\r\ntwo\rthree");
+        // Binary content has no text
+        assert!(column.is_null(1));
+        let content = batch.column_by_name("content").unwrap().as_binary::<i64>();
+        assert_eq!(content.value(1), binary.as_slice());
+
+        options.strip_bom = true;
+        options.normalize_line_endings = true;
+        options.no_raw_content = true;
+        let mut batch = OutputBatch::new_with_options(options);
+        add(&mut batch, text);
+        add(&mut batch, &binary);
+        let batch = batch.create_record_batch_and_reset().unwrap();
+        assert!(batch.column_by_name("content").is_none());
+        let column = batch.column_by_name("text").unwrap().as_string::<i64>();
+        assert_eq!(column.value(0), "one\ntwo\nthree");
+        assert!(column.is_null(1));
+        assert_eq!(
+            batch
+                .column_by_name("size")
+                .unwrap()
+                .as_primitive::<UInt64Type>()
+                .values()
+                .to_vec(),
+            vec![text.len() as u64, binary.len() as u64]
+        );
+    }
+}
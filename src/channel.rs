@@ -1,14 +1,24 @@
+use crate::dedupe::SeenHashes;
+use crate::hash_index::HashKind;
+use crate::incremental::SourceFingerprint;
+use crate::output::{Output, OutputFileStats};
 use crate::progress::OutputCounter;
-use crate::{ConvertionOptions, ParquetSink, new_parquet_writer};
+use crate::provenance::ProvenanceRecorder;
+use crate::{ConvertionOptions, ParquetSink};
 use arrow::record_batch::RecordBatch;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::{DecimalBytes, HumanCount, HumanDuration};
+use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::Write;
-use tracing::{error, info};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{error, info, warn};
 
 pub enum RecordBatchResult {
     Batch(RecordBatch),
+    /// Every batch from the input has been sent
+    InputFinished(PathBuf, SourceFingerprint),
     Errored(std::io::Error),
 }
 
@@ -18,6 +28,9 @@ impl Debug for RecordBatchResult {
             RecordBatchResult::Batch(batch) => {
                 write!(f, "BatchResult(Batch, {} rows)", batch.num_rows())
             }
+            RecordBatchResult::InputFinished(path, _) => {
+                write!(f, "BatchResult(InputFinished, {path:?})")
+            }
             RecordBatchResult::Errored(e) => {
                 write!(f, "BatchResult(Errored, {e})")
             }
@@ -32,6 +45,11 @@ pub struct ConversionCounter {
     pub total_entries_bytes: u64,
     pub output_rows: u64,
     pub output_bytes: u64,
+    pub output_files: Vec<OutputFileStats>,
+    /// True if the conversion was cancelled before every input was converted
+    pub cancelled: bool,
+    /// Inputs that were not fully converted because the conversion was cancelled
+    pub unfinished_inputs: Vec<PathBuf>,
 }
 
 impl Display for ConversionCounter {
@@ -48,32 +66,85 @@ impl Display for ConversionCounter {
     }
 }
 
+/// Cancels a conversion, e.g. when the process is interrupted. Visitors stop reading inputs, and
+/// the output is finished with the rows that have already been read.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug, derive_new::new)]
 pub struct RecordBatchChannel {
     pub(crate) sender: RecordBatchSender,
     pub(crate) receiver: RecordBatchReceiver,
+    cancellation: CancellationToken,
 }
 
 impl RecordBatchChannel {
+    /// A token that cancels every conversion using this channel
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
     fn into_receiver(self) -> RecordBatchReceiver {
         self.receiver
     }
 
+    /// Write every batch sent to the channel to the output, until every sender has been
+    /// dropped. `inputs` are the inputs being converted, which are recorded in the provenance of
+    /// the output. Use [Output::from_writer] to write a single file to any writer.
     pub fn sink_batches(
         self,
         counters: OutputCounter,
-        writer: impl Write + Send,
+        output: Output,
         options: ConvertionOptions,
+        inputs: Vec<PathBuf>,
+    ) -> parquet::errors::Result<ConversionCounter> {
+        let seen_hashes = SeenHashes::for_options(&options, HashKind::Files)?;
+        let seen_chunks = SeenHashes::for_options(&options, HashKind::Chunks)?;
+        self.sink_batches_with_seen_hashes(
+            counters,
+            output,
+            options,
+            inputs,
+            seen_hashes,
+            seen_chunks,
+        )
+    }
+
+    /// [RecordBatchChannel::sink_batches], sharing the seen hashes with the visitors
+    pub(crate) fn sink_batches_with_seen_hashes(
+        self,
+        counters: OutputCounter,
+        output: Output,
+        options: ConvertionOptions,
+        inputs: Vec<PathBuf>,
+        seen_hashes: Option<SeenHashes>,
+        seen_chunks: Option<SeenHashes>,
     ) -> parquet::errors::Result<ConversionCounter> {
         let start = std::time::Instant::now();
-        let mut writer = new_parquet_writer(writer, options.compression)?;
-        let mut sink = ParquetSink::new(&mut writer, options);
+        let provenance = ProvenanceRecorder::new(&options, &inputs, counters.clone());
+        let mut sink = ParquetSink::with_seen_hashes(&output, options, seen_hashes, seen_chunks)?
+            .with_provenance(Arc::new(provenance));
+        let cancellation = self.cancellation_token();
+        let mut finished_inputs = HashSet::new();
 
         let mut total_rows: u64 = 0;
         let rows_before_flush = 10_000;
         let receiver = self.into_receiver();
 
         for msg in receiver.inner.iter() {
+            // Keep receiving after being cancelled, so that visitors are not blocked sending
+            // the rows they have already read. They stop once they see the cancellation.
+            let cancelled = cancellation.is_cancelled();
             match msg {
                 RecordBatchResult::Batch(batch) => {
                     counters.batch_received(&batch);
@@ -84,6 +155,16 @@ impl RecordBatchChannel {
                         sink.flush()?;
                         total_rows = 0;
                     }
+                    if !cancelled {
+                        sink.maybe_checkpoint()?;
+                    }
+                }
+                RecordBatchResult::InputFinished(path, fingerprint) => {
+                    finished_inputs.insert(path.clone());
+                    sink.input_finished(path, fingerprint);
+                    if !cancelled {
+                        sink.maybe_checkpoint()?;
+                    }
                 }
                 RecordBatchResult::Errored(e) => {
                     error!("Error processing: {e:?}");
@@ -91,19 +172,34 @@ impl RecordBatchChannel {
                 }
             }
         }
-        writer.flush()?;
-        let metadata = writer.finish()?;
-        let total_output_bytes: i64 = metadata
-            .row_groups()
-            .iter()
-            .map(|rg| rg.compressed_size())
-            .sum();
+        let cancelled = cancellation.is_cancelled();
+        if cancelled {
+            warn!("Conversion cancelled, finishing the output with the rows already read");
+        }
+        let output_files = sink.finish()?;
+        let total_output_bytes: u64 = output_files.iter().map(|f| f.bytes).sum();
         let duration = start.elapsed();
-        let conversion_counter: ConversionCounter = counters.into();
+        let mut conversion_counter: ConversionCounter = counters.into();
+        if output_files.len() > 1 {
+            for file in &output_files {
+                info!("Wrote {file}");
+            }
+        }
+        conversion_counter.output_files = output_files;
+        conversion_counter.cancelled = cancelled;
+        conversion_counter.unfinished_inputs = inputs
+            .into_iter()
+            .filter(|input| !finished_inputs.contains(input))
+            .collect();
         info!(
-            "File written in {}. size={}, {conversion_counter}",
+            "{} written in {}. size={}, {conversion_counter}",
+            if conversion_counter.output_files.len() > 1 {
+                "Files"
+            } else {
+                "File"
+            },
             HumanDuration(duration),
-            DecimalBytes(total_output_bytes as u64),
+            DecimalBytes(total_output_bytes),
         );
         Ok(conversion_counter)
     }
@@ -111,18 +207,25 @@ impl RecordBatchChannel {
 
 pub fn new_record_batch_channel(size: usize) -> RecordBatchChannel {
     let (batch_tx, batch_rx) = crossbeam_channel::bounded(size);
+    let cancellation = CancellationToken::default();
     RecordBatchChannel::new(
-        RecordBatchSender::new(batch_tx),
+        RecordBatchSender::new(batch_tx, cancellation.clone()),
         RecordBatchReceiver::new(batch_rx),
+        cancellation,
     )
 }
 
 #[derive(Clone, Debug, derive_new::new)]
 pub(crate) struct RecordBatchSender {
     inner: Sender<RecordBatchResult>,
+    cancellation: CancellationToken,
 }
 
 impl RecordBatchSender {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
     pub fn send_batch(&self, result: std::io::Result<RecordBatch>) -> std::io::Result<()> {
         match result {
             Ok(batch) => self.inner.send(RecordBatchResult::Batch(batch)),
@@ -131,9 +234,20 @@ impl RecordBatchSender {
         .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?; // Channel disconnected
         Ok(())
     }
+
+    pub fn send_input_finished(
+        &self,
+        path: PathBuf,
+        fingerprint: SourceFingerprint,
+    ) -> std::io::Result<()> {
+        self.inner
+            .send(RecordBatchResult::InputFinished(path, fingerprint))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?; // Channel disconnected
+        Ok(())
+    }
 }
 
 #[derive(Debug, derive_new::new)]
 pub(crate) struct RecordBatchReceiver {
-    inner: Receiver<RecordBatchResult>,
+    pub(crate) inner: Receiver<RecordBatchResult>,
 }
@@ -0,0 +1,252 @@
+use crate::dedupe::SeenHashes;
+use crate::hasher::HASH_SIZE;
+use arrow::array::{
+    Array, ArrayRef, AsArray, FixedSizeBinaryBuilder, LargeBinaryBuilder, UInt32Builder,
+    UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use fastcdc::v2020::FastCDC;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Smallest and largest average chunk sizes supported by FastCDC
+pub const MIN_CHUNK_SIZE: u64 = fastcdc::v2020::AVERAGE_MIN as u64;
+pub const MAX_CHUNK_SIZE: u64 = fastcdc::v2020::AVERAGE_MAX as u64;
+
+/// Schema metadata marking the `chunks` table, so that the hashes of chunks are not mistaken
+/// for the hashes of files when reading a previous output.
+const TABLE_METADATA_KEY: &str = "archive_to_parquet.table";
+const CHUNKS_TABLE: &str = "chunks";
+
+/// The schema of the `chunks` table, containing the content of each unique chunk.
+pub fn chunks_schema() -> SchemaRef {
+    Arc::new(Schema::new_with_metadata(
+        vec![
+            Field::new("hash", DataType::FixedSizeBinary(HASH_SIZE as i32), false),
+            Field::new("content", DataType::LargeBinary, false),
+        ],
+        HashMap::from([(TABLE_METADATA_KEY.to_string(), CHUNKS_TABLE.to_string())]),
+    ))
+}
+
+/// Returns true if a file with this schema is part of a `chunks` table
+pub(crate) fn is_chunks_schema(schema: &Schema) -> bool {
+    schema
+        .metadata()
+        .get(TABLE_METADATA_KEY)
+        .is_some_and(|table| table == CHUNKS_TABLE)
+}
+
+/// The schema of the `file_chunks` table, mapping the hash of each unique file to the chunks
+/// that its content is made of, in order.
+pub fn file_chunks_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("hash", DataType::FixedSizeBinary(HASH_SIZE as i32), false),
+        Field::new("chunk_index", DataType::UInt32, false),
+        Field::new("offset", DataType::UInt64, false),
+        Field::new("length", DataType::UInt64, false),
+        Field::new(
+            "chunk_hash",
+            DataType::FixedSizeBinary(HASH_SIZE as i32),
+            false,
+        ),
+    ]))
+}
+
+/// Splits content into chunks using content-defined chunking (FastCDC), so that files that
+/// share most of their content share most of their chunks. Each chunk is only output once.
+pub struct Chunker {
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    /// The hashes of the chunks that have already been output. This is separate from the
+    /// hashes of files, as a file and a chunk with the same content have the same hash.
+    seen_chunks: SeenHashes,
+}
+
+/// The chunks and the file to chunk mapping for a batch of files.
+pub struct ChunkedBatch {
+    pub chunks: RecordBatch,
+    pub file_chunks: RecordBatch,
+}
+
+impl Chunker {
+    /// Create a chunker with the given average chunk size. Chunks are between a quarter and four
+    /// times the average size. Chunks in `seen_chunks` are not output.
+    pub(crate) fn new(avg_size: u64, seen_chunks: SeenHashes) -> Self {
+        let avg_size = avg_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) as u32;
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            seen_chunks,
+        }
+    }
+
+    fn chunk(&self, content: &[u8]) -> Vec<(Range<usize>, [u8; HASH_SIZE])> {
+        FastCDC::new(content, self.min_size, self.avg_size, self.max_size)
+            .map(|chunk| {
+                let range = chunk.offset..chunk.offset + chunk.length;
+                let digest = ring::digest::digest(&ring::digest::SHA256, &content[range.clone()]);
+                (range, digest.as_ref().try_into().unwrap())
+            })
+            .collect()
+    }
+
+    /// Chunk the content of a batch with `hash` and `content` columns. The content of every row
+    /// is chunked in parallel.
+    pub fn chunk_batch(&mut self, batch: &RecordBatch) -> Result<ChunkedBatch, ArrowError> {
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| ArrowError::SchemaError(format!("Column {name:?} not found")))
+        };
+        let hashes = column("hash")?.as_fixed_size_binary();
+        let contents = column("content")?.as_binary::<i64>();
+        let file_chunks: Vec<_> = (0..contents.len())
+            .into_par_iter()
+            .map(|idx| self.chunk(contents.value(idx)))
+            .collect();
+
+        let mut chunk_hashes = FixedSizeBinaryBuilder::new(HASH_SIZE as i32);
+        let mut chunk_contents = LargeBinaryBuilder::new();
+        let mut file_hashes = FixedSizeBinaryBuilder::new(HASH_SIZE as i32);
+        let mut indexes = UInt32Builder::new();
+        let mut offsets = UInt64Builder::new();
+        let mut lengths = UInt64Builder::new();
+        let mut mapped_hashes = FixedSizeBinaryBuilder::new(HASH_SIZE as i32);
+        for (idx, chunks) in file_chunks.into_iter().enumerate() {
+            let content = contents.value(idx);
+            for (chunk_index, (range, chunk_hash)) in chunks.into_iter().enumerate() {
+                file_hashes.append_value(hashes.value(idx))?;
+                indexes.append_value(chunk_index as u32);
+                offsets.append_value(range.start as u64);
+                lengths.append_value(range.len() as u64);
+                mapped_hashes.append_value(chunk_hash)?;
+                if self.seen_chunks.insert(&chunk_hash)? {
+                    chunk_hashes.append_value(chunk_hash)?;
+                    chunk_contents.append_value(&content[range]);
+                }
+            }
+        }
+
+        let chunks = RecordBatch::try_new(
+            chunks_schema(),
+            vec![
+                Arc::new(chunk_hashes.finish()) as ArrayRef,
+                Arc::new(chunk_contents.finish()),
+            ],
+        )?;
+        let file_chunks = RecordBatch::try_new(
+            file_chunks_schema(),
+            vec![
+                Arc::new(file_hashes.finish()) as ArrayRef,
+                Arc::new(indexes.finish()),
+                Arc::new(offsets.finish()),
+                Arc::new(lengths.finish()),
+                Arc::new(mapped_hashes.finish()),
+            ],
+        )?;
+        Ok(ChunkedBatch {
+            chunks,
+            file_chunks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunking::{Chunker, chunks_schema};
+    use crate::dedupe::{DedupeBackend, SeenHashes};
+    use crate::hash_index::HashKind;
+    use crate::hasher::HASH_SIZE;
+    use crate::output::{Output, Table, TableWriter};
+    use crate::{ConvertionOptions, TableLayout};
+    use arrow::array::{ArrayRef, AsArray, FixedSizeBinaryArray, LargeBinaryArray, RecordBatch};
+    use arrow::datatypes::UInt64Type;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_chunk_batch() {
+        // Two files that only differ in a small section share most of their chunks
+        let mut state = 1u64;
+        let original: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+        let mut patched = original.clone();
+        patched[500_000..500_100].fill(0);
+
+        let hashes =
+            FixedSizeBinaryArray::try_from_iter([[1u8; HASH_SIZE], [2u8; HASH_SIZE]].into_iter())
+                .unwrap();
+        let contents = LargeBinaryArray::from_iter_values([&original, &patched]);
+        let batch = RecordBatch::try_from_iter([
+            ("hash", Arc::new(hashes) as ArrayRef),
+            ("content", Arc::new(contents) as ArrayRef),
+        ])
+        .unwrap();
+
+        let mut options = ConvertionOptions::const_default();
+        options.layout = TableLayout::Chunked;
+        options.dedupe_backend = DedupeBackend::Disk;
+        let mut chunker = Chunker::new(16 * 1024, SeenHashes::new(&options).unwrap());
+        let chunked = chunker.chunk_batch(&batch).unwrap();
+        let lengths = chunked.file_chunks.column(3).as_primitive::<UInt64Type>();
+        let file_length = |hash: u8| -> u64 {
+            let hashes = chunked.file_chunks.column(0).as_fixed_size_binary();
+            (0..lengths.len())
+                .filter(|idx| hashes.value(*idx)[0] == hash)
+                .map(|idx| lengths.value(idx))
+                .sum()
+        };
+        assert_eq!(file_length(1), original.len() as u64);
+        assert_eq!(file_length(2), patched.len() as u64);
+
+        let stored: usize = chunked
+            .chunks
+            .column(1)
+            .as_binary::<i64>()
+            .iter()
+            .map(|c| c.unwrap().len())
+            .sum();
+        assert!(
+            stored < original.len() + 200 * 1024,
+            "stored {stored} bytes"
+        );
+
+        // Chunks that have already been seen are not output again
+        let again = chunker.chunk_batch(&batch).unwrap();
+        assert_eq!(again.chunks.num_rows(), 0);
+        assert!(again.file_chunks.num_rows() > 0);
+
+        // Nor are the chunks in the chunks table of a previous output
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir.join("output.parquet"));
+        let mut writer =
+            TableWriter::try_new(&output, Table::Chunks, chunks_schema(), &options).unwrap();
+        writer.write(&chunked.chunks).unwrap();
+        writer.finish().unwrap();
+        options.dedupe_against = vec![dir.to_path_buf()];
+        let seen_chunks = SeenHashes::for_options(&options, HashKind::Chunks)
+            .unwrap()
+            .unwrap();
+        assert_eq!(seen_chunks.len(), chunked.chunks.num_rows() as u64);
+        let mut chunker = Chunker::new(16 * 1024, seen_chunks);
+        assert_eq!(chunker.chunk_batch(&batch).unwrap().chunks.num_rows(), 0);
+
+        // The hashes of chunks are not mistaken for the hashes of files
+        options.unique = true;
+        let seen_files = SeenHashes::for_options(&options, HashKind::Files)
+            .unwrap()
+            .unwrap();
+        assert_eq!(seen_files.len(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use std::borrow::Cow;
+
+/// The number of bytes at the start of the content that control bytes are counted in, and that
+/// the charset is detected from.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Content with more than this proportion of control characters is binary
+const MAX_CONTROL_RATIO: f64 = 0.1;
+
+/// Check if the content is valid UTF-8. If the content was truncated then it may end
+/// partway through a multi-byte character, which is allowed.
+#[inline(always)]
+fn is_utf8(v: &[u8], truncated: bool) -> bool {
+    simdutf8::basic::from_utf8(v).is_ok()
+        || (truncated && simdutf8::compat::from_utf8(v).is_err_and(|e| e.error_len().is_none()))
+}
+
+/// Detect the text encoding of content, returning `None` if the content is binary. If the
+/// content was truncated then it may end partway through a character.
+///
+/// Content is classified as follows:
+/// 1. A UTF-16 byte order mark, or a UTF-16 pattern of NUL bytes without one, is UTF-16 if the
+///    content decodes as UTF-16 without too many control characters.
+/// 2. Any other content with a NUL byte, or too many control bytes, is binary.
+/// 3. Content that is valid UTF-8, or mostly valid UTF-8 with a few stray bytes, is UTF-8.
+/// 4. Anything else is text in the legacy encoding that `chardetng` detects, e.g. Latin-1.
+pub(crate) fn detect_encoding(content: &[u8], truncated: bool) -> Option<&'static Encoding> {
+    let sample_truncated = truncated || content.len() > SAMPLE_SIZE;
+    let sample = &content[..content.len().min(SAMPLE_SIZE)];
+    match Encoding::for_bom(sample) {
+        Some((encoding, bom_length)) if encoding != UTF_8 => {
+            return is_utf16_text(encoding, &sample[bom_length..], sample_truncated)
+                .then_some(encoding);
+        }
+        _ => {}
+    }
+    if let Some(encoding) = utf16_without_bom(sample)
+        && is_utf16_text(encoding, sample, sample_truncated)
+    {
+        return Some(encoding);
+    }
+    if sample.contains(&0) || too_many_control(sample.iter().map(|b| *b as char)) {
+        return None;
+    }
+    if is_utf8(content, truncated) || is_mostly_utf8(sample) {
+        return Some(UTF_8);
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(sample, !sample_truncated);
+    Some(detector.guess(None, false))
+}
+
+/// Transcode text content to UTF-8, replacing any invalid sequences. A byte order mark is kept as
+/// U+FEFF unless `strip_bom` is set, and `normalize_line_endings` converts `\r\n` and `\r` to
+/// `\n`.
+pub(crate) fn transcode<'a>(
+    content: &'a [u8],
+    encoding: &'static Encoding,
+    strip_bom: bool,
+    normalize_line_endings: bool,
+) -> Cow<'a, str> {
+    let (text, _) = match strip_bom {
+        true => encoding.decode_with_bom_removal(content),
+        false => encoding.decode_without_bom_handling(content),
+    };
+    match normalize_line_endings && text.contains('\r') {
+        true => Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n")),
+        false => text,
+    }
+}
+
+/// Returns true if more than [MAX_CONTROL_RATIO] of the characters are control characters,
+/// other than whitespace, form feeds and escapes.
+fn too_many_control(chars: impl Iterator<Item = char>) -> bool {
+    let (mut total, mut control) = (0usize, 0usize);
+    for c in chars {
+        total += 1;
+        if c.is_ascii_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b') {
+            control += 1;
+        }
+    }
+    control as f64 > total as f64 * MAX_CONTROL_RATIO
+}
+
+/// UTF-16 text without a byte order mark is mostly ASCII, so has a NUL byte in at least half
+/// of its code units, and always in the same half of them.
+fn utf16_without_bom(sample: &[u8]) -> Option<&'static Encoding> {
+    if sample.len() < 4 {
+        return None;
+    }
+    let (mut even, mut odd) = (0usize, 0usize);
+    for unit in sample.chunks_exact(2) {
+        even += (unit[0] == 0) as usize;
+        odd += (unit[1] == 0) as usize;
+    }
+    let units = sample.len() / 2;
+    match (even, odd) {
+        (0, odd) if odd * 2 >= units => Some(UTF_16LE),
+        (even, 0) if even * 2 >= units => Some(UTF_16BE),
+        _ => None,
+    }
+}
+
+fn is_utf16_text(encoding: &'static Encoding, content: &[u8], truncated: bool) -> bool {
+    // A truncated sample may end partway through a code unit or surrogate pair
+    let content = match truncated {
+        true => &content[..content.len() - content.len().min(4)],
+        false => content,
+    };
+    let (text, had_errors) = encoding.decode_without_bom_handling(content);
+    !had_errors && !text.contains('\0') && !too_many_control(text.chars())
+}
+
+/// Returns true if the content has more valid multi-byte UTF-8 characters than invalid bytes,
+/// i.e. UTF-8 text with a stray byte from another encoding.
+fn is_mostly_utf8(sample: &[u8]) -> bool {
+    let (mut multi_byte, mut invalid) = (0usize, 0usize);
+    for chunk in sample.utf8_chunks() {
+        multi_byte += chunk.valid().chars().filter(|c| !c.is_ascii()).count();
+        invalid += !chunk.invalid().is_empty() as usize;
+    }
+    multi_byte > invalid
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::classify::{detect_encoding, transcode};
+    use encoding_rs::{UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252};
+
+    fn utf16le(text: &str, bom: bool) -> Vec<u8> {
+        let bom = bom.then_some([0xff, 0xfe]).into_iter().flatten();
+        bom.chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect()
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        assert_eq!(detect_encoding(b"", false), Some(UTF_8));
+        assert_eq!(detect_encoding("héllo\n".as_bytes(), false), Some(UTF_8));
+        // Truncated partway through a character
+        assert_eq!(detect_encoding(&"hé".as_bytes()[..2], true), Some(UTF_8));
+        assert_eq!(
+            detect_encoding(b"caf\xc3\xa9 na\xefve \xc3\xa9t\xc3\xa9", false),
+            Some(UTF_8)
+        );
+        assert_eq!(
+            detect_encoding(b"name = Fran\xe7ois\ncity = Montr\xe9al\n", false),
+            Some(WINDOWS_1252)
+        );
+
+        assert_eq!(
+            detect_encoding(&utf16le("fn main() {}\n", true), false),
+            Some(UTF_16LE)
+        );
+        assert_eq!(
+            detect_encoding(&utf16le("fn main() {}\n", false), false),
+            Some(UTF_16LE)
+        );
+        let be: Vec<u8> = "fn main() {}"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        assert_eq!(detect_encoding(&be, false), Some(UTF_16BE));
+
+        assert_eq!(detect_encoding(b"\x7fELF\x02\x01\x01\0\0\0", false), None);
+        // Valid UTF-8, but mostly control bytes
+        assert_eq!(detect_encoding(b"\x01\x02\x03\x04ab\x05\x06", false), None);
+        // Little-endian integers look like UTF-16, but are control characters
+        let integers: Vec<u8> = (1u16..64).flat_map(u16::to_le_bytes).collect();
+        assert_eq!(detect_encoding(&integers, false), None);
+    }
+
+    #[test]
+    fn test_transcode() {
+        let utf16 = utf16le("line one\r\nline two\rdone", true);
+        assert_eq!(
+            transcode(&utf16, UTF_16LE, false, false),
+            "\u{feff}line one\r\nline two\rdone"
+        );
+        assert_eq!(
+            transcode(&utf16, UTF_16LE, true, true),
+            "line one\nline two\ndone"
+        );
+        assert_eq!(
+            transcode(b"Fran\xe7ois", WINDOWS_1252, true, true),
+            "François"
+        );
+        assert_eq!(
+            transcode(b"caf\xc3\xa9 \xff", UTF_8, true, true),
+            "café \u{fffd}"
+        );
+    }
+}
@@ -1,15 +1,20 @@
 use crate::anyreader_walker::{EntryDetails, FileEntry, FormatKind};
 use crate::channel::ConversionCounter;
 use crate::converter::Converter;
+use crate::dedupe::SeenHashes;
+use crate::hash_index::HashKind;
+use crate::incremental::ExistingSources;
+use crate::output::Output;
 use crate::progress::OutputCounter;
 use crate::{ConvertionOptions, RecordBatchChannel, Visitor};
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct StandardConverter<T: Read + Send> {
     pub(super) visitors: Vec<(Visitor, FileEntry<T>)>,
     pub(super) options: ConvertionOptions,
+    pub(super) existing_sources: ExistingSources,
 }
 
 impl<T: Read + Send> Converter<T> for StandardConverter<T> {
@@ -17,6 +22,7 @@ impl<T: Read + Send> Converter<T> for StandardConverter<T> {
         Self {
             visitors: vec![],
             options,
+            existing_sources: ExistingSources::default(),
         }
     }
 
@@ -30,6 +36,14 @@ impl<T: Read + Send> Converter<T> for StandardConverter<T> {
         &self.options
     }
 
+    fn set_existing_sources(&mut self, sources: ExistingSources) {
+        self.existing_sources = sources;
+    }
+
+    fn existing_sources(&self) -> &ExistingSources {
+        &self.existing_sources
+    }
+
     fn add_visitor(
         &mut self,
         visitor: Visitor,
@@ -44,23 +58,57 @@ impl<T: Read + Send> Converter<T> for StandardConverter<T> {
 
     fn convert(
         self,
-        writer: impl Write + Send,
+        output: Output,
         channel: RecordBatchChannel,
     ) -> parquet::errors::Result<ConversionCounter> {
-        let counters: OutputCounter = Default::default();
+        self.convert_with(output, channel, Default::default(), |mut visitor, entry| {
+            visitor.start_walking(entry)
+        })
+    }
+}
 
+impl<T: Read + Send> StandardConverter<T> {
+    /// Walk each input on a thread pool, calling `walk` to start each visitor, and sink the
+    /// batches they send to the output. Hashes already in the output are shared with every
+    /// visitor when duplicates are skipped, so that their entries are not read again.
+    pub(super) fn convert_with(
+        self,
+        output: Output,
+        channel: RecordBatchChannel,
+        counters: OutputCounter,
+        walk: impl Fn(Visitor, FileEntry<T>) + Sync,
+    ) -> parquet::errors::Result<ConversionCounter> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.options.threads.into())
             .build()
             .unwrap();
+        let inputs = self
+            .visitors
+            .iter()
+            .map(|(visitor, _)| visitor.input_path().clone())
+            .collect();
+        let seen_hashes =
+            SeenHashes::for_output(&self.options, &self.existing_sources, HashKind::Files)?;
+        let seen_chunks =
+            SeenHashes::for_output(&self.options, &self.existing_sources, HashKind::Chunks)?;
         pool.in_place_scope(|scope| {
             for (mut visitor, entry) in self.visitors {
-                scope.spawn(move |_| {
-                    visitor.start_walking(entry);
-                });
+                if let Some(seen_hashes) = &seen_hashes
+                    && self.options.skips_duplicates()
+                {
+                    visitor.set_seen_hashes(seen_hashes.clone());
+                }
+                let walk = &walk;
+                scope.spawn(move |_| walk(visitor, entry));
             }
-            let counters = channel.sink_batches(counters, writer, self.options)?;
-            Ok(counters)
+            channel.sink_batches_with_seen_hashes(
+                counters,
+                output,
+                self.options,
+                inputs,
+                seen_hashes,
+                seen_chunks,
+            )
         })
     }
 }
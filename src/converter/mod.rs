@@ -3,12 +3,15 @@ mod progress;
 
 use crate::anyreader_walker::{EntryDetails, FormatKind};
 use crate::channel::{ConversionCounter, RecordBatchChannel};
+use crate::incremental::{ExistingSources, SourceFingerprint};
+use crate::output::Output;
 use crate::{ConvertionOptions, Visitor};
 pub use base::StandardConverter;
 pub use progress::ProgressBarConverter;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 pub trait Converter<T: Read + Send>: Sized {
     fn new(options: ConvertionOptions) -> Self;
@@ -17,6 +20,11 @@ pub trait Converter<T: Read + Send>: Sized {
 
     fn options(&self) -> &ConvertionOptions;
 
+    /// Set the inputs converted by a previous run. Inputs that have not changed since are skipped.
+    fn set_existing_sources(&mut self, sources: ExistingSources);
+
+    fn existing_sources(&self) -> &ExistingSources;
+
     fn add_paths(
         &mut self,
         paths: impl IntoIterator<Item = impl AsRef<Path>>,
@@ -25,13 +33,12 @@ pub trait Converter<T: Read + Send>: Sized {
     where
         Self: Converter<BufReader<File>>,
     {
-        let mut readers = vec![];
         for path in paths.into_iter() {
             let reader = File::open(&path)?;
-            let size = reader.metadata()?.len();
-            readers.push((path, size, BufReader::new(reader)));
+            let fingerprint = SourceFingerprint::from_metadata(&reader.metadata()?);
+            self.add_reader(path.as_ref(), fingerprint, BufReader::new(reader), channel)?;
         }
-        self.add_readers(readers, channel)
+        Ok(())
     }
 
     fn add_readers(
@@ -42,16 +49,39 @@ pub trait Converter<T: Read + Send>: Sized {
         // let batch_size = self.options().batch_size;
 
         for (path, size, reader) in readers.into_iter() {
-            let visitor = Visitor::new(
-                path.as_ref(),
-                channel.sender.clone(),
-                self.options().clone(),
-            );
-            self.add_visitor(visitor, path.as_ref().to_path_buf(), size, reader)?
+            let fingerprint = SourceFingerprint {
+                size,
+                modified: None,
+            };
+            self.add_reader(path.as_ref(), fingerprint, reader, channel)?;
         }
         Ok(())
     }
 
+    fn add_reader(
+        &mut self,
+        path: &Path,
+        fingerprint: SourceFingerprint,
+        reader: T,
+        channel: &RecordBatchChannel,
+    ) -> std::io::Result<()> {
+        let source = path.to_string_lossy();
+        let existing = self.existing_sources();
+        if existing.contains(&source, &fingerprint) {
+            info!("Skipping {source:?}: already converted");
+            return Ok(());
+        } else if existing.contains_source(&source) {
+            warn!("{source:?} has changed since it was last converted, converting it again");
+        }
+        let visitor = Visitor::new(
+            path,
+            fingerprint,
+            channel.sender.clone(),
+            self.options().clone(),
+        );
+        self.add_visitor(visitor, path.to_path_buf(), fingerprint.size, reader)
+    }
+
     fn add_visitor(
         &mut self,
         visitor: Visitor,
@@ -62,7 +92,17 @@ pub trait Converter<T: Read + Send>: Sized {
 
     fn convert(
         self,
-        writer: impl Write + Send,
+        output: Output,
         channel: RecordBatchChannel,
     ) -> parquet::errors::Result<ConversionCounter>;
+
+    /// Convert the inputs into a single file written to a writer, such as a buffer, socket or
+    /// pipe. See [Output::from_writer].
+    fn convert_to_writer(
+        self,
+        writer: impl Write + Send + 'static,
+        channel: RecordBatchChannel,
+    ) -> parquet::errors::Result<ConversionCounter> {
+        self.convert(Output::from_writer(writer), channel)
+    }
 }
@@ -1,9 +1,11 @@
 use crate::anyreader_walker::{EntryDetails, FormatKind};
 use crate::channel::ConversionCounter;
+use crate::incremental::ExistingSources;
+use crate::output::Output;
 use crate::progress::{Counters, OutputCounter};
 use crate::{Converter, ConvertionOptions, RecordBatchChannel, StandardConverter, Visitor};
 use indicatif::{MultiProgress, ProgressBar, ProgressBarIter};
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -35,6 +37,14 @@ impl<T: Read + Send> Converter<T> for ProgressBarConverter<T> {
         self.converter.options()
     }
 
+    fn set_existing_sources(&mut self, sources: ExistingSources) {
+        self.converter.set_existing_sources(sources);
+    }
+
+    fn existing_sources(&self) -> &ExistingSources {
+        self.converter.existing_sources()
+    }
+
     fn add_visitor(
         &mut self,
         visitor: Visitor,
@@ -50,7 +60,7 @@ impl<T: Read + Send> Converter<T> for ProgressBarConverter<T> {
 
     fn convert(
         self,
-        writer: impl Write + Send,
+        output: Output,
         channel: RecordBatchChannel,
     ) -> parquet::errors::Result<ConversionCounter> {
         let counters: OutputCounter = Default::default();
@@ -65,23 +75,14 @@ impl<T: Read + Send> Converter<T> for ProgressBarConverter<T> {
             ),
         );
         progress_bar.enable_steady_tick(Duration::from_millis(250));
-        let writer = progress_bar.wrap_write(writer);
+        let output = output.with_progress_bar(progress_bar);
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(self.options().threads.into())
-            .build()
-            .unwrap();
-        pool.in_place_scope(|scope| {
-            for (mut visitor, entry) in self.converter.visitors {
-                let progress = &self.progress;
-                scope.spawn(move |_| {
-                    entry.get_ref().start_progress_bar(progress);
-                    visitor.start_walking(entry);
-                });
-            }
-            let counter = channel.sink_batches(counters, writer, self.converter.options)?;
-            Ok(counter)
-        })
+        let progress = &self.progress;
+        self.converter
+            .convert_with(output, channel, counters, |mut visitor, entry| {
+                entry.get_ref().start_progress_bar(progress);
+                visitor.start_walking(entry);
+            })
     }
 }
 
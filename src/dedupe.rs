@@ -0,0 +1,437 @@
+use crate::hash_index::{Hash, HashKind, read_file_hashes, read_hashes};
+use crate::hasher::HASH_SIZE;
+use crate::incremental::ExistingSources;
+use crate::{ConvertionOptions, TableLayout};
+use parquet::errors::ParquetError;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{debug, info, warn};
+
+/// How the hashes that have already been seen are stored when deduplicating.
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Default, clap::ValueEnum, strum::EnumString, strum::Display,
+)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum DedupeBackend {
+    /// Every hash is held in memory. Exact, but uses around 50 bytes per unique file
+    #[default]
+    Memory,
+    /// Hashes are stored in a hash table in a temporary file, using a constant amount of memory.
+    /// Exact, but slower than holding the hashes in memory
+    Disk,
+    /// A Bloom filter sized for the expected number of files. Uses a fixed amount of memory,
+    /// but a unique file is dropped as a duplicate with the configured false-positive rate.
+    /// Only for --unique with the single layout
+    Bloom,
+}
+
+/// Number of shards in [SeenHashes]
+const SHARDS: usize = 16;
+
+/// The set of hashes that have already been written, shared between the visitors and the sink.
+/// The hashes are split between shards by their last byte, each behind its own lock, so that
+/// visitors inserting hashes at the same time rarely wait for each other.
+#[derive(Clone)]
+pub(crate) struct SeenHashes(Arc<[Mutex<Shard>]>);
+
+impl Debug for SeenHashes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeenHashes")
+            .field("shards", &self.0.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SeenHashes {
+    pub fn new(options: &ConvertionOptions) -> std::io::Result<Self> {
+        let shards = (0..SHARDS)
+            .map(|_| Shard::new(options).map(Mutex::new))
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self(shards))
+    }
+
+    /// The seen hashes of a kind needed by a conversion with these options, seeded with the
+    /// hashes of every path in [ConvertionOptions::dedupe_against]. Returns `None` if those
+    /// hashes do not need to be tracked. The hashes of chunks are only tracked with the
+    /// chunked layout.
+    pub fn for_options(
+        options: &ConvertionOptions,
+        kind: HashKind,
+    ) -> parquet::errors::Result<Option<Self>> {
+        let tracked = match kind {
+            HashKind::Files => options.tracks_hashes(),
+            HashKind::Chunks => options.layout == TableLayout::Chunked,
+        };
+        if !tracked {
+            return Ok(None);
+        }
+        let seen_hashes = Self::new(options)?;
+        for path in &options.dedupe_against {
+            read_hashes(path, &seen_hashes, kind)?;
+        }
+        Ok(Some(seen_hashes))
+    }
+
+    /// [SeenHashes::for_options], also seeded with the hashes of the files already written to
+    /// the output by previous runs. Files converted again when resuming or converting
+    /// incrementally are then not written twice.
+    pub fn for_output(
+        options: &ConvertionOptions,
+        existing: &ExistingSources,
+        kind: HashKind,
+    ) -> parquet::errors::Result<Option<Self>> {
+        let Some(seen_hashes) = Self::for_options(options, kind)? else {
+            return Ok(None);
+        };
+        for path in existing.files() {
+            read_file_hashes(path, &seen_hashes, kind).map_err(|e| {
+                ParquetError::General(format!("Error reading hashes from {path:?}: {e}"))
+            })?;
+        }
+        if !existing.files().is_empty() {
+            info!(
+                "Read {} hashes from {} files in the existing output",
+                seen_hashes.len(),
+                existing.files().len()
+            );
+        }
+        Ok(Some(seen_hashes))
+    }
+
+    fn shard(&self, hash: &Hash) -> MutexGuard<'_, Shard> {
+        let shard = &self.0[hash[HASH_SIZE - 1] as usize % self.0.len()];
+        shard.lock().expect("seen hashes lock poisoned")
+    }
+
+    /// Add a hash to the set, returning true if it had not been seen before.
+    pub fn insert(&self, hash: &Hash) -> std::io::Result<bool> {
+        self.shard(hash).insert(hash)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0
+            .iter()
+            .map(|shard| shard.lock().expect("seen hashes lock poisoned").len())
+            .sum()
+    }
+
+    /// Call a function with every hash in the set. A Bloom filter does not store the hashes,
+    /// so cannot be iterated.
+    pub fn for_each(&self, mut f: impl FnMut(Hash) -> std::io::Result<()>) -> std::io::Result<()> {
+        for shard in self.0.iter() {
+            shard
+                .lock()
+                .expect("seen hashes lock poisoned")
+                .for_each(&mut f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single shard of [SeenHashes]
+enum Shard {
+    Memory(HashSet<Hash>),
+    Disk(DiskHashSet),
+    Bloom(BloomFilter),
+}
+
+impl Shard {
+    fn new(options: &ConvertionOptions) -> std::io::Result<Self> {
+        Ok(match options.dedupe_backend {
+            DedupeBackend::Memory => Self::Memory(HashSet::new()),
+            DedupeBackend::Disk => Self::Disk(DiskHashSet::new()?),
+            DedupeBackend::Bloom => Self::Bloom(BloomFilter::new(
+                options.dedupe_expected_items.div_ceil(SHARDS as u64),
+                options.dedupe_false_positive_rate,
+            )),
+        })
+    }
+
+    fn insert(&mut self, hash: &Hash) -> std::io::Result<bool> {
+        match self {
+            Shard::Memory(hashes) => Ok(hashes.insert(*hash)),
+            Shard::Disk(hashes) => hashes.insert(hash),
+            Shard::Bloom(filter) => Ok(filter.insert(hash)),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Shard::Memory(hashes) => hashes.len() as u64,
+            Shard::Disk(hashes) => hashes.len,
+            Shard::Bloom(filter) => filter.len,
+        }
+    }
+
+    fn for_each(&self, f: impl FnMut(Hash) -> std::io::Result<()>) -> std::io::Result<()> {
+        match self {
+            Shard::Memory(hashes) => hashes.iter().copied().try_for_each(f),
+            Shard::Disk(hashes) => hashes.for_each(f),
+            Shard::Bloom(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the hashes in a Bloom filter cannot be listed",
+            )),
+        }
+    }
+}
+
+const SLOT_SIZE: usize = HASH_SIZE;
+const SLOTS_PER_BUCKET: usize = 128;
+const BUCKET_SIZE: usize = SLOT_SIZE * SLOTS_PER_BUCKET;
+const INITIAL_BUCKETS: u64 = 1024;
+/// The table is doubled in size once it is this full
+const MAX_LOAD_FACTOR: f64 = 0.75;
+
+/// An open-addressing hash table of hashes stored in a temporary file. The file is split into
+/// buckets of fixed-size slots, and a hash is stored in the first empty slot of the bucket
+/// chosen by its first bytes, or of the following buckets if that bucket is full. Empty slots
+/// are all zeros, so the all-zero hash is tracked separately.
+///
+/// The file is written to [std::env::temp_dir], which can be changed with `TMPDIR`.
+pub(crate) struct DiskHashSet {
+    dir: PathBuf,
+    file: File,
+    buckets: u64,
+    len: u64,
+    contains_zero: bool,
+    generation: usize,
+}
+
+impl DiskHashSet {
+    pub fn new() -> std::io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "archive-to-parquet-dedupe-{}",
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let file = Self::create_table(&dir, 0, INITIAL_BUCKETS)?;
+        Ok(Self {
+            dir,
+            file,
+            buckets: INITIAL_BUCKETS,
+            len: 0,
+            contains_zero: false,
+            generation: 0,
+        })
+    }
+
+    fn create_table(
+        dir: &std::path::Path,
+        generation: usize,
+        buckets: u64,
+    ) -> std::io::Result<File> {
+        let path = dir.join(format!("table-{generation}"));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.set_len(buckets * BUCKET_SIZE as u64)?;
+        Ok(file)
+    }
+
+    pub fn insert(&mut self, hash: &Hash) -> std::io::Result<bool> {
+        if hash == &[0; HASH_SIZE] {
+            return Ok(!std::mem::replace(&mut self.contains_zero, true));
+        }
+        let inserted = Self::insert_into(&mut self.file, self.buckets, hash)?;
+        if inserted {
+            self.len += 1;
+            if self.len as f64 > (self.buckets * SLOTS_PER_BUCKET as u64) as f64 * MAX_LOAD_FACTOR {
+                self.grow()?;
+            }
+        }
+        Ok(inserted)
+    }
+
+    fn insert_into(file: &mut File, buckets: u64, hash: &Hash) -> std::io::Result<bool> {
+        let mut bucket = u64::from_le_bytes(hash[..8].try_into().unwrap()) % buckets;
+        let mut buffer = [0u8; BUCKET_SIZE];
+        loop {
+            let offset = bucket * BUCKET_SIZE as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            for (slot, value) in buffer.chunks_exact(SLOT_SIZE).enumerate() {
+                if value == hash {
+                    return Ok(false);
+                }
+                if value == [0; SLOT_SIZE] {
+                    file.seek(SeekFrom::Start(offset + (slot * SLOT_SIZE) as u64))?;
+                    file.write_all(hash)?;
+                    return Ok(true);
+                }
+            }
+            // The bucket is full, so the hash is stored in the next bucket
+            bucket = (bucket + 1) % buckets;
+        }
+    }
+
+    /// Double the number of buckets, moving every hash to a new file
+    fn grow(&mut self) -> std::io::Result<()> {
+        let buckets = self.buckets * 2;
+        let generation = self.generation + 1;
+        debug!(
+            "Growing dedupe table to {buckets} buckets ({} hashes)",
+            self.len
+        );
+        let mut file = Self::create_table(&self.dir, generation, buckets)?;
+        self.for_each_stored(|hash| Self::insert_into(&mut file, buckets, &hash).map(|_| ()))?;
+        std::fs::remove_file(self.dir.join(format!("table-{}", self.generation)))?;
+        self.file = file;
+        self.buckets = buckets;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn for_each_stored(
+        &self,
+        mut f: impl FnMut(Hash) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut reader = BufReader::with_capacity(BUCKET_SIZE * 16, self.file.try_clone()?);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut slot = [0u8; SLOT_SIZE];
+        for _ in 0..self.buckets * SLOTS_PER_BUCKET as u64 {
+            reader.read_exact(&mut slot)?;
+            if slot != [0; SLOT_SIZE] {
+                f(slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, mut f: impl FnMut(Hash) -> std::io::Result<()>) -> std::io::Result<()> {
+        if self.contains_zero {
+            f([0; HASH_SIZE])?;
+        }
+        self.for_each_stored(f)
+    }
+}
+
+impl Drop for DiskHashSet {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A Bloom filter of hashes. As the hashes are already uniformly distributed, the bit positions
+/// are derived from the hash itself using double hashing.
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    expected_items: u64,
+    len: u64,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: u64, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits =
+            ((-(expected_items as f64) * false_positive_rate.ln()) / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / expected_items as f64) * ln2).round() as u32;
+        debug!(
+            "Bloom filter with {num_bits} bits and {num_hashes} hashes for {expected_items} items"
+        );
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes: num_hashes.max(1),
+            expected_items,
+            len: 0,
+        }
+    }
+
+    /// The word and mask of each bit for a hash
+    fn bits(&self, hash: &Hash) -> impl Iterator<Item = (usize, u64)> + use<> {
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    /// Returns true if the hash is (probably) in the filter
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.bits(hash)
+            .all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    /// Add a hash to the filter, returning true if it was not already (probably) present.
+    pub fn insert(&mut self, hash: &Hash) -> bool {
+        let inserted = !self.contains(hash);
+        for (word, mask) in self.bits(hash) {
+            self.bits[word] |= mask;
+        }
+        if inserted {
+            self.len += 1;
+            if self.len == self.expected_items + 1 {
+                warn!(
+                    "More than {} unique hashes have been seen, the Bloom filter false-positive rate will increase",
+                    self.expected_items
+                );
+            }
+        }
+        inserted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedupe::{BloomFilter, DiskHashSet, INITIAL_BUCKETS};
+    use crate::hash_index::Hash;
+    use std::collections::HashSet;
+
+    fn hash(i: u64) -> Hash {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &i.to_le_bytes());
+        digest.as_ref().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_disk_hash_set() {
+        let mut set = DiskHashSet::new().unwrap();
+        let count = 200_000;
+        for i in 0..count {
+            assert!(set.insert(&hash(i)).unwrap());
+        }
+        assert!(set.buckets > INITIAL_BUCKETS);
+        for i in (0..count).step_by(7) {
+            assert!(!set.insert(&hash(i)).unwrap());
+        }
+        assert!(set.insert(&[0; 32]).unwrap());
+        assert!(!set.insert(&[0; 32]).unwrap());
+
+        let mut stored = HashSet::new();
+        set.for_each(|hash| {
+            stored.insert(hash);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(stored.len() as u64, count + 1);
+        let dir = set.dir.clone();
+        drop(set);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(100_000, 0.01);
+        for i in 0..100_000 {
+            filter.insert(&hash(i));
+        }
+        for i in 0..100_000 {
+            assert!(!filter.insert(&hash(i)));
+        }
+        let false_positives = (100_000..200_000)
+            .filter(|i| filter.contains(&hash(*i)))
+            .count();
+        assert!(false_positives < 2_000, "{false_positives} false positives");
+    }
+}
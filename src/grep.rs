@@ -0,0 +1,210 @@
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::hybrid::{BuildError, LazyStateID};
+use regex_automata::util::start;
+use regex_automata::util::syntax;
+use regex_automata::{Anchored, MatchKind};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Regex patterns that the content of a file must match at least one of to be output. The
+/// patterns are compiled into a single lazy DFA, which is run over the content as it is read so
+/// that the content does not need to be buffered to be searched.
+///
+/// Patterns match bytes rather than UTF-8 text, so also match inside binary files: `.` matches
+/// any byte other than `\n`, and `\w`, `\d` and `\b` only match ASCII.
+#[derive(Debug, Clone, Default)]
+pub struct ContentPatterns {
+    patterns: Vec<String>,
+    dfa: Option<Arc<DFA>>,
+}
+
+impl ContentPatterns {
+    pub const fn const_default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            dfa: None,
+        }
+    }
+
+    pub fn new(patterns: Vec<String>) -> Result<Self, Box<BuildError>> {
+        if patterns.is_empty() {
+            return Ok(Self::const_default());
+        }
+        let dfa = DFA::builder()
+            .configure(DFA::config().match_kind(MatchKind::All))
+            .syntax(syntax::Config::new().unicode(false).utf8(false))
+            .build_many(&patterns)?;
+        Ok(Self {
+            patterns,
+            dfa: Some(Arc::new(dfa)),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// A matcher to search the content of files with, or `None` if there are no patterns.
+    pub(crate) fn matcher(&self) -> Option<ContentMatcher> {
+        let dfa = self.dfa.clone()?;
+        let cache = dfa.create_cache();
+        let mut matcher = ContentMatcher {
+            matched: vec![false; dfa.pattern_len()],
+            remaining: dfa.pattern_len(),
+            state: LazyStateID::default(),
+            dfa,
+            cache,
+        };
+        matcher.reset();
+        Some(matcher)
+    }
+}
+
+impl PartialEq for ContentPatterns {
+    fn eq(&self, other: &Self) -> bool {
+        self.patterns == other.patterns
+    }
+}
+
+impl Display for ContentPatterns {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.patterns)
+    }
+}
+
+/// Searches a stream of content for [ContentPatterns], recording which patterns matched.
+#[derive(Debug)]
+pub(crate) struct ContentMatcher {
+    dfa: Arc<DFA>,
+    cache: Cache,
+    state: LazyStateID,
+    matched: Vec<bool>,
+    /// The number of patterns that have not matched yet
+    remaining: usize,
+}
+
+impl ContentMatcher {
+    fn reset(&mut self) {
+        // Unicode is disabled, so there are no quit bytes and searches cannot fail
+        self.state = self
+            .dfa
+            .start_state(
+                &mut self.cache,
+                &start::Config::new().anchored(Anchored::No),
+            )
+            .expect("content patterns cannot fail to start");
+        self.matched.fill(false);
+        self.remaining = self.matched.len();
+    }
+
+    fn record_matches(&mut self) {
+        for idx in 0..self.dfa.match_len(&self.cache, self.state) {
+            let pattern = self.dfa.match_pattern(&self.cache, self.state, idx);
+            if !std::mem::replace(&mut self.matched[pattern.as_usize()], true) {
+                self.remaining -= 1;
+            }
+        }
+    }
+
+    /// Search the next part of the content
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.remaining == 0 {
+                return;
+            }
+            self.state = self
+                .dfa
+                .next_state(&mut self.cache, self.state, byte)
+                .expect("content patterns cannot fail");
+            if self.state.is_match() {
+                self.record_matches();
+            }
+        }
+    }
+
+    /// Finish searching the content, returning the index of each pattern that matched. The
+    /// matcher is then reset to search the next file.
+    pub fn finish(&mut self) -> Vec<usize> {
+        if self.remaining > 0 {
+            self.state = self
+                .dfa
+                .next_eoi_state(&mut self.cache, self.state)
+                .expect("content patterns cannot fail");
+            if self.state.is_match() {
+                self.record_matches();
+            }
+        }
+        let matched = (0..self.matched.len())
+            .filter(|idx| self.matched[*idx])
+            .collect();
+        self.reset();
+        matched
+    }
+}
+
+/// A writer that searches everything written to it with a [ContentMatcher], if there is one.
+pub(crate) struct MatchingWriter<'a, W: Write> {
+    inner: W,
+    matcher: Option<&'a mut ContentMatcher>,
+}
+
+impl<'a, W: Write> MatchingWriter<'a, W> {
+    pub fn new(inner: W, matcher: Option<&'a mut ContentMatcher>) -> Self {
+        Self { inner, matcher }
+    }
+}
+
+impl<W: Write> Write for MatchingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(matcher) = &mut self.matcher {
+            matcher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grep::ContentPatterns;
+
+    #[test]
+    fn test_content_matcher() {
+        let patterns = ContentPatterns::new(vec![
+            r"log4j-core-2\.1[0-4]\.".into(),
+            "CVE-[0-9]{4}-[0-9]+".into(),
+            r"^\x7fELF".into(),
+        ])
+        .unwrap();
+        let mut matcher = patterns.matcher().unwrap();
+
+        // Matches are found across writes
+        for part in [
+            b"deps: log4j-co".as_slice(),
+            b"re-2.14.1.jar\n",
+            b"CVE-2021-",
+            b"44228",
+        ] {
+            matcher.update(part);
+        }
+        assert_eq!(matcher.finish(), vec![0, 1]);
+
+        matcher.update(b"\x7fELF\x02\x01");
+        assert_eq!(matcher.finish(), vec![2]);
+        // The start of the content is only matched at the start
+        matcher.update(b"data \x7fELF");
+        assert_eq!(matcher.finish(), Vec::<usize>::new());
+
+        assert!(ContentPatterns::new(vec!["(".into()]).is_err());
+        assert!(ContentPatterns::new(vec![]).unwrap().matcher().is_none());
+    }
+}
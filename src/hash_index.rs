@@ -0,0 +1,198 @@
+use crate::chunking::is_chunks_schema;
+use crate::dedupe::SeenHashes;
+use crate::hasher::HASH_SIZE;
+use crate::output::{OutputFormat, read_file};
+use arrow::array::{Array, AsArray};
+use parquet::errors::ParquetError;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The first bytes of a hash index file
+const MAGIC: &[u8; 8] = b"A2PHASH1";
+
+pub type Hash = [u8; HASH_SIZE];
+
+/// A compact file containing a set of file hashes, used to deduplicate files against previous
+/// runs without reading their output. The file is [MAGIC] followed by the hashes.
+pub struct HashIndex;
+
+impl HashIndex {
+    pub(crate) fn write(path: &Path, hashes: &SeenHashes) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        hashes.for_each(|hash| writer.write_all(&hash))?;
+        writer.into_inner()?.sync_all()?;
+        info!("Wrote {} hashes to {path:?}", hashes.len());
+        Ok(())
+    }
+
+    /// Returns true if the file starts with the hash index magic bytes
+    fn is_index(path: &Path) -> std::io::Result<bool> {
+        let mut magic = [0; MAGIC.len()];
+        match File::open(path)?.read_exact(&mut magic) {
+            Ok(()) => Ok(&magic == MAGIC),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(path: &Path, hashes: &SeenHashes) -> std::io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        reader.read_exact(&mut [0; MAGIC.len()])?;
+        let mut hash = [0; HASH_SIZE];
+        loop {
+            match reader.read_exact(&mut hash) {
+                Ok(()) => {
+                    hashes.insert(&hash)?;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Which hashes to read from a previous output
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum HashKind {
+    /// The hashes of files, from a hash index or any table except the `chunks` table
+    Files,
+    /// The hashes of chunks, from the `chunks` table written with the chunked layout
+    Chunks,
+}
+
+/// Read every hash of a kind from a hash index, a Parquet or Arrow IPC file with a `hash`
+/// column or a directory of them, such as a partitioned dataset. Files in a directory that
+/// cannot be read, such as those left behind by a conversion that crashed, are skipped.
+pub(crate) fn read_hashes(
+    path: &Path,
+    hashes: &SeenHashes,
+    kind: HashKind,
+) -> parquet::errors::Result<()> {
+    let before = hashes.len();
+    let with_context =
+        |e: ParquetError| ParquetError::General(format!("Error reading hashes from {path:?}: {e}"));
+    if path.is_dir() {
+        for file in data_files(path)? {
+            if let Err(e) = read_file_hashes(&file, hashes, kind) {
+                warn!("Skipping {file:?}, the hashes could not be read: {e}");
+            }
+        }
+    } else if HashIndex::is_index(path)? {
+        // A hash index only has the hashes of files
+        if kind == HashKind::Files {
+            HashIndex::read(path, hashes)?;
+        }
+    } else {
+        read_file_hashes(path, hashes, kind).map_err(with_context)?;
+    }
+    info!("Read {} new hashes from {path:?}", hashes.len() - before);
+    Ok(())
+}
+
+/// Read the `hash` column of a file written in any output format, if the file has hashes of
+/// the given kind.
+pub(crate) fn read_file_hashes(
+    path: &Path,
+    hashes: &SeenHashes,
+    kind: HashKind,
+) -> parquet::errors::Result<()> {
+    let (_, reader) = read_file(path, Some(&["hash"]))?;
+    if reader.schema().fields().is_empty() {
+        return Err(ParquetError::General("no hash column".to_string()));
+    }
+    if is_chunks_schema(&reader.schema()) != (kind == HashKind::Chunks) {
+        return Ok(());
+    }
+    for batch in reader {
+        let batch = batch?;
+        let column = batch.column(0).as_fixed_size_binary();
+        if column.value_length() != HASH_SIZE as i32 {
+            return Err(ParquetError::General(format!(
+                "hash column has {} byte values, expected {HASH_SIZE}",
+                column.value_length()
+            )));
+        }
+        for idx in 0..column.len() {
+            hashes.insert(column.value(idx).try_into().unwrap())?;
+        }
+    }
+    Ok(())
+}
+
+/// Every Parquet or Arrow IPC file in a directory, skipping hidden files and files starting
+/// with `_`.
+fn data_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let extensions = [
+        OutputFormat::Parquet,
+        OutputFormat::Ipc,
+        OutputFormat::IpcStream,
+    ]
+    .map(|format| format.extension());
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with(['.', '_']) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|ext| extensions.iter().any(|e| ext == *e))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedupe::SeenHashes;
+    use crate::hash_index::{HashIndex, HashKind, read_hashes};
+    use crate::output::{Output, Table, TableWriter};
+    use crate::{ConvertionOptions, FileEntry, batch::OutputBatch, batch::arrow_schema};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_read_hashes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let options = ConvertionOptions::const_default();
+        let mut batch = OutputBatch::new_with_options(options.clone());
+        let mut entry = FileEntry::from_bytes("test", b"hello world".as_slice()).unwrap();
+        batch
+            .add_record(Path::new("input.tar"), PathBuf::new(), &mut entry)
+            .unwrap();
+        let output = Output::new(dir.join("output.parquet"));
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, arrow_schema(&options), &options).unwrap();
+        writer
+            .write(&batch.create_record_batch_and_reset().unwrap())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let hashes = SeenHashes::new(&options).unwrap();
+        read_hashes(&dir.join("output.parquet"), &hashes, HashKind::Files).unwrap();
+        assert_eq!(hashes.len(), 1);
+        let from_dir = SeenHashes::new(&options).unwrap();
+        read_hashes(dir, &from_dir, HashKind::Files).unwrap();
+        assert_eq!(from_dir.len(), 1);
+
+        let index = dir.join("hashes.idx");
+        hashes.insert(&[1; 32]).unwrap();
+        HashIndex::write(&index, &hashes).unwrap();
+        let from_index = SeenHashes::new(&options).unwrap();
+        read_hashes(&index, &from_index, HashKind::Files).unwrap();
+        assert_eq!(from_index.len(), 2);
+        assert!(!from_index.insert(&[1; 32]).unwrap());
+    }
+}
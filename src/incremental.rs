@@ -0,0 +1,178 @@
+use crate::ConvertionOptions;
+use crate::output::{DeltaTable, Journal, Output};
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Identifies a version of an input, so that an input that has changed since it was last
+/// converted is converted again.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub struct SourceFingerprint {
+    pub size: u64,
+    /// The modification time of the input in milliseconds since the epoch, if known.
+    pub modified: Option<i64>,
+}
+
+impl SourceFingerprint {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_millis() as i64);
+        Self {
+            size: metadata.len(),
+            modified,
+        }
+    }
+}
+
+/// The inputs that have been fully converted to an existing output, and the fingerprints they
+/// had when they were converted, along with the data files of the output.
+///
+/// Inputs are read from the record of each completed run, so the rows of an input that was not
+/// finished are never counted as converted: for a Delta table this is the `commitInfo` of each
+/// commit, otherwise it is the [Journal] inside the output directory.
+#[derive(Debug, Default)]
+pub struct ExistingSources {
+    sources: HashMap<String, HashSet<SourceFingerprint>>,
+    files: Vec<PathBuf>,
+}
+
+impl ExistingSources {
+    /// Read the inputs that have been converted to an existing output dataset, recovering the
+    /// output if a previous conversion was interrupted. An output that does not exist contains
+    /// no sources.
+    pub fn read(output: &Output, options: &ConvertionOptions) -> parquet::errors::Result<Self> {
+        if !output.path().exists() {
+            return Ok(Self::default());
+        }
+        if options.output_format.is_delta() {
+            return DeltaTable::existing_sources(output);
+        }
+        Journal::recover(output, options)
+    }
+
+    pub fn insert(&mut self, source: &str, fingerprint: SourceFingerprint) {
+        if let Some(fingerprints) = self.sources.get_mut(source) {
+            fingerprints.insert(fingerprint);
+        } else {
+            self.sources
+                .insert(source.to_string(), HashSet::from([fingerprint]));
+        }
+    }
+
+    /// Insert an input recorded with [input_json]
+    pub(crate) fn insert_json(&mut self, input: &Value) {
+        let fingerprint = SourceFingerprint {
+            size: input["size"].as_u64().unwrap_or_default(),
+            modified: input["modified"].as_i64(),
+        };
+        self.insert(input["source"].as_str().unwrap_or_default(), fingerprint);
+    }
+
+    /// Record a data file of the output
+    pub(crate) fn add_file(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    /// The data files of the output
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Add all the sources from another set of sources
+    pub fn extend(&mut self, other: ExistingSources) {
+        for (source, fingerprints) in other.sources {
+            self.sources.entry(source).or_default().extend(fingerprints);
+        }
+        self.files.extend(other.files);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub fn contains_source(&self, source: &str) -> bool {
+        self.sources.contains_key(source)
+    }
+
+    /// Returns true if the input has already been converted, and has not changed since.
+    pub fn contains(&self, source: &str, fingerprint: &SourceFingerprint) -> bool {
+        self.sources
+            .get(source)
+            .is_some_and(|fingerprints| fingerprints.contains(fingerprint))
+    }
+}
+
+/// An input and its fingerprint, as recorded in a [Journal] or a Delta commit.
+pub(crate) fn input_json(source: &Path, fingerprint: &SourceFingerprint) -> Value {
+    json!({
+        "source": source.to_string_lossy(),
+        "size": fingerprint.size,
+        "modified": fingerprint.modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::OutputBatch;
+    use crate::incremental::{ExistingSources, SourceFingerprint};
+    use crate::output::{Journal, Output};
+    use crate::{ConvertionOptions, FileEntry, ParquetSink};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_read_existing_sources() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let mut options = ConvertionOptions::const_default();
+        options.incremental = true;
+        assert!(ExistingSources::read(&output, &options).unwrap().is_empty());
+
+        let fingerprint = SourceFingerprint {
+            size: 11,
+            modified: Some(1000),
+        };
+        let mut sink = ParquetSink::new(&output, options.clone()).unwrap();
+        for source in ["input.tar", "unfinished.tar"] {
+            let mut batch =
+                OutputBatch::new_with_options(options.clone()).with_source_fingerprint(fingerprint);
+            let mut entry = FileEntry::from_bytes("test", b"hello world".as_slice()).unwrap();
+            batch
+                .add_record(Path::new(source), PathBuf::new(), &mut entry)
+                .unwrap();
+            sink.write_batch(batch.create_record_batch_and_reset().unwrap())
+                .unwrap();
+        }
+        sink.input_finished(PathBuf::from("input.tar"), fingerprint);
+        // Files left behind by a run that crashed are never read
+        let crashed = dir.join("part-00000-crashed.parquet");
+        std::fs::write(&crashed, b"PAR1").unwrap();
+        std::fs::write(Journal::in_progress_path(&crashed), b"PAR1").unwrap();
+        let files = sink.finish().unwrap();
+
+        let existing = ExistingSources::read(&output, &options).unwrap();
+        assert_eq!(existing.len(), 1);
+        assert!(existing.contains("input.tar", &fingerprint));
+        assert!(!existing.contains(
+            "input.tar",
+            &SourceFingerprint {
+                size: 12,
+                ..fingerprint
+            }
+        ));
+        assert!(existing.contains_source("input.tar"));
+        // Rows alone do not mean that an input was converted
+        assert!(!existing.contains_source("unfinished.tar"));
+        assert_eq!(existing.files(), [files[0].path.clone()]);
+        assert!(!Journal::in_progress_path(&crashed).exists());
+    }
+}
@@ -4,25 +4,55 @@ use indicatif::DecimalBytes;
 pub use parquet::basic::Compression;
 use std::fmt::Display;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 
 mod anyreader;
 mod anyreader_walker;
 mod batch;
 mod channel;
+mod chunking;
+mod classify;
 mod converter;
+mod dedupe;
+mod grep;
+mod hash_index;
 mod hasher;
+mod incremental;
+mod output;
+mod path_filter;
+mod predicate;
 mod progress;
+mod properties;
+mod provenance;
+mod secrets;
 mod sink;
 mod visitor;
 
 pub use anyreader_walker::{AnyWalker, ArchiveStack, EntryDetails, FileEntry, FormatKind};
-pub use channel::{ConversionCounter, RecordBatchChannel, new_record_batch_channel};
+pub use channel::{
+    CancellationToken, ConversionCounter, RecordBatchChannel, new_record_batch_channel,
+};
+pub use chunking::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
 pub use converter::{Converter, ProgressBarConverter, StandardConverter};
-pub use sink::{IncludeType, ParquetSink, new_parquet_writer};
+pub use dedupe::DedupeBackend;
+pub use grep::ContentPatterns;
+pub use incremental::{ExistingSources, SourceFingerprint};
+pub use output::{
+    FormatWriter, Journal, Output, OutputFileStats, OutputFormat, OutputWriter, PartitionBy,
+    SortBy, Table, TableWriter,
+};
+pub use path_filter::PathFilter;
+pub use predicate::Predicate;
+pub use properties::{ConfigFile, ParquetOptions};
+pub use provenance::{Provenance, ProvenanceCounters};
+pub use sink::{
+    IncludeType, ParquetSink, TableLayout, new_parquet_writer, new_parquet_writer_with_options,
+};
 pub use visitor::*;
 
-#[allow(clippy::too_many_arguments)]
-#[derive(Debug, Clone, derive_new::new)]
+/// Options for a conversion. Start from [ConvertionOptions::const_default] or
+/// [ConvertionOptions::new] and set the fields that differ from the defaults.
+#[derive(Debug, Clone)]
 pub struct ConvertionOptions {
     pub threads: NonZeroUsize,
     pub include: IncludeType,
@@ -33,9 +63,87 @@ pub struct ConvertionOptions {
     pub batch_count: usize,
     pub batch_size: Byte,
     pub extract_strings: bool,
+    pub no_content: bool,
+    pub max_content_size: Option<Byte>,
+    pub layout: TableLayout,
+    pub max_file_size: Option<Byte>,
+    pub max_file_rows: Option<u64>,
+    pub partition_by: Option<PartitionBy>,
+    pub max_open_partitions: usize,
+    pub max_partition_memory: Byte,
+    pub output_format: OutputFormat,
+    pub incremental: bool,
+    pub checkpoint_interval: Option<u64>,
+    pub parquet: ParquetOptions,
+    pub sort_by: Option<SortBy>,
+    pub max_sort_memory: Byte,
+    pub chunk_size: Byte,
+    /// Hash indexes or Parquet outputs of previous runs. Files with a hash in any of them are
+    /// treated as already seen when deduplicating.
+    pub dedupe_against: Vec<PathBuf>,
+    /// Write a hash index of every file seen, including those in `dedupe_against`, to this path
+    pub hash_index: Option<PathBuf>,
+    pub dedupe_backend: DedupeBackend,
+    /// The number of unique files that the Bloom filter backend is sized for
+    pub dedupe_expected_items: u64,
+    /// The false-positive rate of the Bloom filter backend, once it holds the expected items
+    pub dedupe_false_positive_rate: f64,
+    /// Which files to output, by their full nested path
+    pub paths: PathFilter,
+    /// Which nested archives to extract, by their full nested path. Archives that are not
+    /// extracted are output as files. The inputs themselves are always extracted.
+    pub archives: PathFilter,
+    /// Only output files matching this predicate
+    pub predicate: Option<Predicate>,
+    /// Only output files with content that matches any of these patterns
+    pub content_patterns: ContentPatterns,
+    /// Add a column listing the content patterns that each file matched
+    pub matched_patterns_column: bool,
+    /// Add `is_text` and `encoding` columns with the detected text encoding of each file. The
+    /// content is needed to classify it, so this cannot be used with `no_content`.
+    pub classify_content: bool,
+    /// Add a `text` column with the content of text files transcoded to UTF-8
+    pub text_column: bool,
+    /// Remove byte order marks from the `text` column
+    pub strip_bom: bool,
+    /// Convert `\r\n` and `\r` line endings to `\n` in the `text` column
+    pub normalize_line_endings: bool,
+    /// Omit the `content` column, e.g. when only the `text` column is needed. Requires
+    /// `text_column`, use `no_content` to omit all content.
+    pub no_raw_content: bool,
+    /// Scan content for secrets, writing what is found to the `findings` table
+    pub scan_secrets: bool,
 }
 
 impl ConvertionOptions {
+    /// Options with the given settings, and the defaults of [ConvertionOptions::const_default]
+    /// for every other field.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        threads: NonZeroUsize,
+        include: IncludeType,
+        unique: bool,
+        compression: Compression,
+        min_size: Option<Byte>,
+        max_size: Option<Byte>,
+        batch_count: usize,
+        batch_size: Byte,
+        extract_strings: bool,
+    ) -> Self {
+        Self {
+            threads,
+            include,
+            unique,
+            compression,
+            min_size,
+            max_size,
+            batch_count,
+            batch_size,
+            extract_strings,
+            ..Self::const_default()
+        }
+    }
+
     pub const fn const_default() -> Self {
         Self {
             threads: NonZeroUsize::new(8).unwrap(),
@@ -48,9 +156,61 @@ impl ConvertionOptions {
             // Also needs changing in the Args struct inside main.rs
             batch_size: Byte::from_u64_with_unit(100, Unit::MB).unwrap(),
             extract_strings: false,
+            no_content: false,
+            max_content_size: None,
+            layout: TableLayout::Single,
+            max_file_size: None,
+            max_file_rows: None,
+            partition_by: None,
+            max_open_partitions: 64,
+            // Also needs changing in the Args struct inside main.rs
+            max_partition_memory: Byte::from_u64_with_unit(1, Unit::GB).unwrap(),
+            output_format: OutputFormat::Parquet,
+            incremental: false,
+            checkpoint_interval: None,
+            parquet: ParquetOptions::const_default(),
+            sort_by: None,
+            // Also needs changing in the Args struct inside main.rs
+            max_sort_memory: Byte::from_u64_with_unit(1, Unit::GB).unwrap(),
+            // Also needs changing in the Args struct inside main.rs
+            chunk_size: Byte::from_u64_with_unit(64, Unit::KiB).unwrap(),
+            dedupe_against: Vec::new(),
+            hash_index: None,
+            dedupe_backend: DedupeBackend::Memory,
+            dedupe_expected_items: 10_000_000,
+            dedupe_false_positive_rate: 0.0001,
+            paths: PathFilter::const_default(),
+            archives: PathFilter::const_default(),
+            predicate: None,
+            content_patterns: ContentPatterns::const_default(),
+            matched_patterns_column: false,
+            classify_content: false,
+            text_column: false,
+            strip_bom: false,
+            normalize_line_endings: false,
+            no_raw_content: false,
+            scan_secrets: false,
         }
     }
 
+    /// Returns true if each run appends new files to a dataset directory, rather than writing
+    /// files with fixed names.
+    pub fn is_appending(&self) -> bool {
+        self.incremental || self.output_format.is_delta() || self.checkpoint_interval.is_some()
+    }
+
+    /// Returns true if the hashes of files that have been seen are tracked, to deduplicate them
+    /// or to write a hash index.
+    pub(crate) fn tracks_hashes(&self) -> bool {
+        self.unique || self.layout != TableLayout::Single || self.hash_index.is_some()
+    }
+
+    /// Returns true if duplicate files are skipped by the visitors as they are read. The other
+    /// layouts write every file to the files table, and only deduplicate the content.
+    pub(crate) fn skips_duplicates(&self) -> bool {
+        self.unique && self.layout == TableLayout::Single
+    }
+
     #[inline(always)]
     pub fn get_size_range(&self) -> Option<std::ops::Range<Byte>> {
         match (self.min_size, self.max_size) {
@@ -66,8 +226,8 @@ impl Display for ConvertionOptions {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ConvertionOptions(threads={}, include={:?}, unique={}, compression={:?}",
-            self.threads, self.include, self.unique, self.compression
+            "ConvertionOptions(threads={}, include={}, unique={}, output_format={}, compression={:?}",
+            self.threads, self.include, self.unique, self.output_format, self.compression
         )?;
         if let Some(min_size) = &self.min_size {
             write!(f, ", min_size={}", DecimalBytes(min_size.as_u64()))?;
@@ -82,9 +242,102 @@ impl Display for ConvertionOptions {
         }
         write!(
             f,
-            ", batch_count={}, batch_size={:#.1})",
+            ", batch_count={}, batch_size={:#.1}, no_content={}",
             self.batch_count,
-            DecimalBytes(self.batch_size.as_u64())
-        )
+            DecimalBytes(self.batch_size.as_u64()),
+            self.no_content
+        )?;
+        if self.classify_content {
+            write!(f, ", classify_content=true")?;
+        }
+        if self.text_column {
+            write!(
+                f,
+                ", text_column=true, strip_bom={}, normalize_line_endings={}, no_raw_content={}",
+                self.strip_bom, self.normalize_line_endings, self.no_raw_content
+            )?;
+        }
+        if let Some(max_content_size) = &self.max_content_size {
+            write!(
+                f,
+                ", max_content_size={}",
+                DecimalBytes(max_content_size.as_u64())
+            )?;
+        } else {
+            write!(f, ", max_content_size=None")?;
+        }
+        if !self.paths.is_empty() {
+            write!(f, ", paths=({})", self.paths)?;
+        }
+        if !self.archives.is_empty() {
+            write!(f, ", archives=({})", self.archives)?;
+        }
+        if let Some(predicate) = &self.predicate {
+            write!(f, ", filter={predicate}")?;
+        }
+        if !self.content_patterns.is_empty() {
+            write!(f, ", grep={}", self.content_patterns)?;
+        }
+        if self.scan_secrets {
+            write!(f, ", scan_secrets=true")?;
+        }
+        write!(f, ", layout={}", self.layout)?;
+        if self.layout == TableLayout::Chunked {
+            write!(
+                f,
+                ", chunk_size={:#.1}",
+                DecimalBytes(self.chunk_size.as_u64())
+            )?;
+        }
+        if let Some(max_file_size) = &self.max_file_size {
+            write!(
+                f,
+                ", max_file_size={}",
+                DecimalBytes(max_file_size.as_u64())
+            )?;
+        } else {
+            write!(f, ", max_file_size=None")?;
+        }
+        write!(
+            f,
+            ", max_file_rows={:?}, incremental={}, checkpoint_interval={:?}",
+            self.max_file_rows, self.incremental, self.checkpoint_interval
+        )?;
+        if self.tracks_hashes() {
+            write!(f, ", dedupe_backend={}", self.dedupe_backend)?;
+            if self.dedupe_backend == DedupeBackend::Bloom {
+                write!(
+                    f,
+                    ", dedupe_expected_items={}, dedupe_false_positive_rate={}",
+                    self.dedupe_expected_items, self.dedupe_false_positive_rate
+                )?;
+            }
+        }
+        if !self.dedupe_against.is_empty() {
+            write!(f, ", dedupe_against={:?}", self.dedupe_against)?;
+        }
+        if let Some(hash_index) = &self.hash_index {
+            write!(f, ", hash_index={hash_index:?}")?;
+        }
+        if let Some(sort_by) = &self.sort_by {
+            write!(
+                f,
+                ", sort_by={sort_by}, max_sort_memory={:#.1}",
+                DecimalBytes(self.max_sort_memory.as_u64())
+            )?;
+        }
+        if self.parquet != ParquetOptions::const_default() {
+            write!(f, ", parquet={}", self.parquet)?;
+        }
+        if let Some(partition_by) = &self.partition_by {
+            write!(
+                f,
+                ", partition_by={partition_by}, max_open_partitions={}, max_partition_memory={:#.1})",
+                self.max_open_partitions,
+                DecimalBytes(self.max_partition_memory.as_u64())
+            )
+        } else {
+            write!(f, ", partition_by=None)")
+        }
     }
 }
@@ -1,7 +1,9 @@
 use anyhow::{Context, bail};
 use archive_to_parquet::{
-    ConversionCounter, ConvertionOptions, IncludeType, ProgressBarConverter, StandardConverter,
-    new_record_batch_channel,
+    ConfigFile, ContentPatterns, ConversionCounter, ConvertionOptions, DedupeBackend,
+    ExistingSources, IncludeType, Journal, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, Output, OutputFormat,
+    ParquetOptions, PartitionBy, PathFilter, Predicate, ProgressBarConverter, SortBy,
+    StandardConverter, TableLayout, new_record_batch_channel,
 };
 use archive_to_parquet::{Converter, RecordBatchChannel};
 use byte_unit::Byte;
@@ -11,8 +13,8 @@ pub use parquet::basic::Compression as ParquetCompression;
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Read, Stderr, Write, stderr};
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
-use tracing::{Level, error, info};
+use std::path::{Path, PathBuf};
+use tracing::{Level, error, info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
@@ -25,7 +27,8 @@ const DEFAULT_OPTS: ConvertionOptions = ConvertionOptions::const_default();
 
 #[derive(Debug, Clone, Parser)]
 struct Args {
-    /// Output Parquet file to create
+    /// Output file to create, or the output directory if partitioning or writing a Delta table.
+    /// Pass "-" to stream the output to stdout
     output: PathBuf,
 
     /// Input paths to read. Pass "-" to read paths from stdin
@@ -50,18 +53,124 @@ struct Args {
     #[clap(long)]
     unique: bool,
 
-    /// Only output text files, skipping binary files
-    #[clap(long, value_enum, default_value_t=DEFAULT_OPTS.include)]
+    /// Which files to output: all, text, binary or a comma separated list of
+    /// MIME type patterns (e.g. "image/*,application/pdf").
+    #[clap(long, default_value_t=DEFAULT_OPTS.include)]
     include: IncludeType,
 
+    /// Only output files with a full nested path (e.g. "nested.tar/src/main.py") matching one of
+    /// these globs, e.g. "**/*.py". Can be given multiple times.
+    #[clap(long)]
+    include_path: Vec<String>,
+
+    /// Skip files with a full nested path matching any of these globs without reading them,
+    /// e.g. "**/node_modules/**". Can be given multiple times.
+    #[clap(long)]
+    exclude_path: Vec<String>,
+
+    /// Only extract archives with a full nested path matching one of these globs. Archives that
+    /// are not extracted are output as files. The inputs themselves are always extracted.
+    /// Can be given multiple times
+    #[clap(long)]
+    include_archive: Vec<String>,
+
+    /// Do not extract archives with a full nested path matching any of these globs, e.g.
+    /// "**/*.jar". The inputs themselves are always extracted. Can be given multiple times.
+    #[clap(long)]
+    exclude_archive: Vec<String>,
+
+    /// Only output files matching a filter expression over their metadata, e.g.
+    /// "size < 10MB and format != 'executable' and path ~ '\.rs$' and depth <= 3".
+    /// Fields: path, extension, format, mime_type, size, depth, is_text and is_binary.
+    #[clap(long)]
+    filter: Option<Predicate>,
+
+    /// Only output files with content matching any of these regexes, e.g. "log4j-core-2\.1[0-4]".
+    /// Patterns match bytes, so also match inside binary files. Can be given multiple times.
+    #[clap(long = "grep", value_name = "PATTERN")]
+    grep: Vec<String>,
+
+    /// Add a `matched_patterns` column listing the --grep patterns that each file matched
+    #[clap(long)]
+    matched_patterns_column: bool,
+
+    /// Add `is_text` and `encoding` columns, with the text encoding detected from the byte order
+    /// mark, the proportion of NUL and control bytes, and charset detection. `encoding` is null
+    /// for binary files
+    #[clap(long)]
+    classify_content: bool,
+
+    /// Add a `text` column with the content of text files transcoded to UTF-8 from their detected
+    /// encoding. The value is null for binary files
+    #[clap(long)]
+    text_column: bool,
+
+    /// Remove byte order marks from the `text` column
+    #[clap(long, requires = "text_column")]
+    strip_bom: bool,
+
+    /// Convert CRLF and CR line endings to LF in the `text` column
+    #[clap(long, requires = "text_column")]
+    normalize_line_endings: bool,
+
+    /// Omit the raw `content` column, keeping only the `text` column
+    #[clap(long, requires = "text_column")]
+    no_raw_content: bool,
+
+    /// Scan content for secrets such as AWS keys, private keys and GitHub tokens as it is read.
+    /// Findings are written to a `findings` table next to the output, with the path and hash
+    /// of the file, the rule, the byte offset and the redacted match
+    #[clap(long)]
+    scan_secrets: bool,
+
     /// Number of threads to use when extracting.
     /// Defaults to number of CPU cores
     #[clap(long, default_value_t = default_threads())]
     threads: NonZeroUsize,
 
-    /// Compression to use
-    #[clap(long, default_value_t = DEFAULT_OPTS.compression)]
-    compression: ParquetCompression,
+    /// Format to write the output in.
+    /// Defaults to "ipc-stream" when writing to stdout, otherwise "parquet"
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Compression to use, with an optional level (e.g. "zstd(19)").
+    /// Defaults to the compression in the config file, or "snappy".
+    #[clap(long)]
+    compression: Option<ParquetCompression>,
+
+    /// TOML file containing Parquet writer options. Options given on the command line
+    /// take precedence over the file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Compression to use for a single column, e.g. "content=zstd(19)". Can be given multiple
+    /// times.
+    #[clap(long, value_parser = parse_column_compression)]
+    column_compression: Vec<(String, ParquetCompression)>,
+
+    /// Maximum number of rows in each Parquet row group
+    #[clap(long)]
+    max_row_group_size: Option<usize>,
+
+    /// Maximum size of each Parquet data page
+    #[clap(long)]
+    data_page_size: Option<Byte>,
+
+    /// Maximum number of rows in each Parquet data page
+    #[clap(long)]
+    data_page_row_limit: Option<usize>,
+
+    /// Comma separated list of columns to write bloom filters for. Pass "" to disable
+    #[clap(long, value_delimiter = ',')]
+    bloom_filter_columns: Option<Vec<String>>,
+
+    /// Comma separated list of columns to write page statistics for. Pass "" to disable
+    #[clap(long, value_delimiter = ',')]
+    statistics_columns: Option<Vec<String>>,
+
+    /// Comma separated list of columns to dictionary encode. Pass "" to disable
+    #[clap(long, value_delimiter = ',')]
+    dictionary_columns: Option<Vec<String>>,
 
     /// Number of batches to buffer in memory at once.
     #[clap(long, default_value_t = DEFAULT_OPTS.batch_count)]
@@ -82,6 +191,148 @@ struct Args {
     /// Extract strings from executables
     #[clap(long)]
     extract_executable_strings: bool,
+
+    /// Only output metadata (paths, sizes and hashes), without the file content
+    #[clap(long)]
+    no_content: bool,
+
+    /// Maximum amount of content to store for each file. Larger files are truncated,
+    /// but are still hashed in full and have their original size recorded.
+    #[clap(long)]
+    max_content_size: Option<Byte>,
+
+    /// How to split the output into tables. The "normalised" layout writes the content of
+    /// each unique file to a separate "blobs" table next to the output file. The "chunked"
+    /// layout splits content into chunks, writing each unique chunk to a "chunks" table and
+    /// the chunks of each file to a "file_chunks" table.
+    #[clap(long, value_enum, default_value_t=DEFAULT_OPTS.layout)]
+    layout: TableLayout,
+
+    /// Roll over to a new output file once the current one reaches this size.
+    /// Files are named with a sequential suffix, e.g. "output-00000.parquet".
+    #[clap(long)]
+    max_file_size: Option<Byte>,
+
+    /// Roll over to a new output file once the current one contains this many rows.
+    /// Files are named with a sequential suffix, e.g. "output-00000.parquet".
+    #[clap(long)]
+    max_file_rows: Option<u64>,
+
+    /// Write a Hive-style partitioned dataset, with a directory for each
+    /// partition inside the output directory (e.g. "output/format=executable/").
+    #[clap(long, value_enum)]
+    partition_by: Option<PartitionBy>,
+
+    /// Maximum number of partition files to keep open at once.
+    /// The least recently used file is closed when this is exceeded.
+    #[clap(long, default_value_t = DEFAULT_OPTS.max_open_partitions)]
+    max_open_partitions: usize,
+
+    /// Maximum memory to use for buffering rows across all open partition files.
+    #[clap(long, default_value = "1GB")]
+    max_partition_memory: Byte,
+
+    /// Skip inputs that are already in the output dataset and have not changed since. The output
+    /// is a directory, and each run adds new files to it. The inputs that each run converts are
+    /// recorded in a journal inside the output directory, or in the Delta log. The size and
+    /// modification time of each input are written to the "source_size" and "source_mtime" columns.
+    #[clap(long)]
+    incremental: bool,
+
+    /// Checkpoint the output every this many seconds, finishing all open files and recording
+    /// every input that has been fully converted in a journal inside the output directory.
+    #[clap(long)]
+    checkpoint_interval: Option<u64>,
+
+    /// Resume an interrupted conversion from the journal written with --checkpoint-interval,
+    /// skipping inputs that have already been converted.
+    #[clap(long, requires = "checkpoint_interval")]
+    resume: bool,
+
+    /// Sort the rows of each table. Rows that do not fit in memory are spilled to temporary
+    /// files and merged. When checkpointing, the rows written between each checkpoint are sorted.
+    #[clap(long, value_enum)]
+    sort_by: Option<SortBy>,
+
+    /// Maximum memory to use for buffering rows while sorting, for each table.
+    #[clap(long, default_value = "1GB")]
+    max_sort_memory: Byte,
+
+    /// Treat files with a hash in a previous output as duplicates. Takes a Parquet file, a
+    /// directory of Parquet files or a hash index written with --write-hash-index. With the
+    /// chunked layout, chunks in the chunks table of a previous output are not written again.
+    /// Can be given multiple times.
+    #[clap(long)]
+    dedupe_against: Vec<PathBuf>,
+
+    /// Write a compact index of the hash of every file seen, including the hashes read with
+    /// --dedupe-against, to this path. Pass it to --dedupe-against in the next run.
+    #[clap(long)]
+    write_hash_index: Option<PathBuf>,
+
+    /// How to store the hashes of files that have been seen when deduplicating
+    #[clap(long, value_enum, default_value_t = DEFAULT_OPTS.dedupe_backend)]
+    dedupe_backend: DedupeBackend,
+
+    /// Number of unique files to size the "bloom" dedupe backend for
+    #[clap(long, default_value_t = DEFAULT_OPTS.dedupe_expected_items)]
+    dedupe_expected_items: u64,
+
+    /// False-positive rate of the "bloom" dedupe backend. This is the chance that a unique file
+    /// is dropped as a duplicate, once the expected number of files have been seen.
+    #[clap(long, default_value_t = DEFAULT_OPTS.dedupe_false_positive_rate)]
+    dedupe_false_positive_rate: f64,
+
+    /// Average size of the chunks that content is split into with the "chunked" layout.
+    /// Chunks are between a quarter and four times this size.
+    #[clap(long, default_value = "64KiB")]
+    chunk_size: Byte,
+}
+
+fn parse_column_compression(value: &str) -> Result<(String, ParquetCompression), String> {
+    let (column, compression) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected COLUMN=COMPRESSION, got {value:?}"))?;
+    let compression = compression.parse().map_err(|e| format!("{e}"))?;
+    Ok((column.to_string(), compression))
+}
+
+/// Merge the Parquet writer options from the config file and the command line
+fn parquet_options(args: &Args) -> anyhow::Result<(ParquetCompression, ParquetOptions)> {
+    let config = match &args.config {
+        Some(path) => ConfigFile::read(path).with_context(|| format!("Reading config {path:?}"))?,
+        None => ConfigFile::default(),
+    };
+    let columns = |columns: &Option<Vec<String>>| {
+        columns.as_ref().map(|columns| {
+            columns
+                .iter()
+                .filter(|column| !column.is_empty())
+                .cloned()
+                .collect()
+        })
+    };
+    let mut parquet = config.parquet;
+    parquet.max_row_group_size = args.max_row_group_size.or(parquet.max_row_group_size);
+    parquet.data_page_size_limit = args
+        .data_page_size
+        .map(|size| size.as_u64() as usize)
+        .or(parquet.data_page_size_limit);
+    parquet.data_page_row_count_limit = args
+        .data_page_row_limit
+        .or(parquet.data_page_row_count_limit);
+    parquet.bloom_filter_columns =
+        columns(&args.bloom_filter_columns).or(parquet.bloom_filter_columns);
+    parquet.statistics_columns = columns(&args.statistics_columns).or(parquet.statistics_columns);
+    parquet.dictionary_columns = columns(&args.dictionary_columns).or(parquet.dictionary_columns);
+    parquet
+        .column_compression
+        .extend(args.column_compression.iter().cloned());
+    let compression = args
+        .compression
+        .or(config.compression)
+        .unwrap_or(DEFAULT_OPTS.compression);
+    Ok((compression, parquet))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -90,19 +341,150 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 fn do_main(args: Args) -> anyhow::Result<()> {
-    let options = ConvertionOptions::new(
-        args.threads,
-        args.include,
-        args.unique,
-        args.compression,
-        args.min_size,
-        args.max_size,
-        args.batch_count,
-        args.batch_size,
-        args.extract_executable_strings,
-    );
+    if args.no_content && (args.include.is_text() || args.include.is_binary()) {
+        bail!(
+            "--include {} cannot be used with --no-content",
+            args.include
+        );
+    }
+    if args.no_content && args.filter.as_ref().is_some_and(|f| f.uses_content()) {
+        bail!("--filter with is_text or is_binary cannot be used with --no-content");
+    }
+    if args.matched_patterns_column && args.grep.is_empty() {
+        bail!("--matched-patterns-column requires --grep");
+    }
+    if args.no_content && args.classify_content {
+        bail!("--classify-content cannot be used with --no-content");
+    }
+    if args.no_content && args.text_column {
+        bail!("--text-column cannot be used with --no-content");
+    }
+    if args.text_column && args.layout != TableLayout::Single {
+        bail!("--text-column cannot be used with --layout {}", args.layout);
+    }
+    if args.no_content && args.layout != TableLayout::Single {
+        bail!("--layout {} cannot be used with --no-content", args.layout);
+    }
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&args.chunk_size.as_u64()) {
+        bail!(
+            "--chunk-size must be between {} and {} bytes",
+            MIN_CHUNK_SIZE,
+            MAX_CHUNK_SIZE
+        );
+    }
+    if !args.dedupe_against.is_empty() && !args.unique && args.layout == TableLayout::Single {
+        bail!("--dedupe-against requires --unique, or the normalised or chunked layout");
+    }
+    if args.dedupe_backend == DedupeBackend::Bloom && args.write_hash_index.is_some() {
+        bail!("--write-hash-index cannot be used with the bloom dedupe backend");
+    }
+    // With the other layouts a false positive would drop content that the files table refers to
+    if args.dedupe_backend == DedupeBackend::Bloom
+        && (!args.unique || args.layout != TableLayout::Single)
+    {
+        bail!("The bloom dedupe backend can only be used with --unique and the single layout");
+    }
+    if !(args.dedupe_false_positive_rate > 0.0 && args.dedupe_false_positive_rate < 1.0) {
+        bail!("--dedupe-false-positive-rate must be between 0 and 1");
+    }
+    let to_stdout = args.output == Path::new("-");
+    if to_stdout
+        && (args.layout != TableLayout::Single
+            || args.partition_by.is_some()
+            || args.max_file_size.is_some()
+            || args.max_file_rows.is_some())
+    {
+        bail!("Only a single output file can be written to stdout");
+    }
+    let format = match args.format {
+        Some(format) => format,
+        None if to_stdout => OutputFormat::IpcStream,
+        None => OutputFormat::Parquet,
+    };
+    if args.scan_secrets && (to_stdout || format.is_delta() || args.incremental) {
+        bail!("--scan-secrets cannot be used with stdout, --format delta or --incremental");
+    }
+    if format.is_delta() && (to_stdout || args.layout != TableLayout::Single) {
+        bail!("--format delta can only be used with a single table written to a directory");
+    }
+    if args.incremental && (to_stdout || args.layout != TableLayout::Single) {
+        bail!("--incremental can only be used with a single table written to a directory");
+    }
+    if args.checkpoint_interval.is_some() {
+        if to_stdout || args.layout != TableLayout::Single || format.is_delta() || args.scan_secrets
+        {
+            bail!(
+                "--checkpoint-interval can only be used with a single table written to a directory"
+            );
+        }
+        // Incremental conversions always continue from the journal
+        if !args.resume && !args.incremental && Journal::exists(&Output::new(&args.output)) {
+            bail!(
+                "{:?} contains a checkpoint journal, pass --resume to continue the conversion",
+                args.output
+            );
+        }
+    }
+    let (compression, parquet) = parquet_options(&args)?;
+    let paths = PathFilter::new(args.include_path, args.exclude_path)
+        .context("Invalid --include-path or --exclude-path glob")?;
+    let archives = PathFilter::new(args.include_archive, args.exclude_archive)
+        .context("Invalid --include-archive or --exclude-archive glob")?;
+    let content_patterns = ContentPatterns::new(args.grep).context("Invalid --grep pattern")?;
+    let options = ConvertionOptions {
+        threads: args.threads,
+        include: args.include,
+        unique: args.unique,
+        compression,
+        min_size: args.min_size,
+        max_size: args.max_size,
+        batch_count: args.batch_count,
+        batch_size: args.batch_size,
+        extract_strings: args.extract_executable_strings,
+        no_content: args.no_content,
+        max_content_size: args.max_content_size,
+        layout: args.layout,
+        max_file_size: args.max_file_size,
+        max_file_rows: args.max_file_rows,
+        partition_by: args.partition_by,
+        max_open_partitions: args.max_open_partitions,
+        max_partition_memory: args.max_partition_memory,
+        output_format: format,
+        incremental: args.incremental,
+        checkpoint_interval: args.checkpoint_interval,
+        parquet,
+        sort_by: args.sort_by,
+        max_sort_memory: args.max_sort_memory,
+        chunk_size: args.chunk_size,
+        dedupe_against: args.dedupe_against,
+        hash_index: args.write_hash_index,
+        dedupe_backend: args.dedupe_backend,
+        dedupe_expected_items: args.dedupe_expected_items,
+        dedupe_false_positive_rate: args.dedupe_false_positive_rate,
+        paths,
+        archives,
+        predicate: args.filter,
+        content_patterns,
+        matched_patterns_column: args.matched_patterns_column,
+        classify_content: args.classify_content,
+        text_column: args.text_column,
+        strip_bom: args.strip_bom,
+        normalize_line_endings: args.normalize_line_endings,
+        no_raw_content: args.no_raw_content,
+        scan_secrets: args.scan_secrets,
+    };
 
     let channel = new_record_batch_channel(options.batch_count);
+    let cancellation = channel.cancellation_token();
+    ctrlc::set_handler(move || {
+        if cancellation.is_cancelled() {
+            // Interrupted twice, exit without finishing the output
+            std::process::exit(130);
+        }
+        warn!("Interrupted, finishing the output. Interrupt again to exit immediately");
+        cancellation.cancel();
+    })
+    .context("Setting signal handler")?;
 
     let paths = get_paths(args.paths)?;
 
@@ -111,8 +493,9 @@ fn do_main(args: Args) -> anyhow::Result<()> {
             channel,
             paths,
             args.log_file,
-            args.output,
+            args.output.clone(),
             args.urls,
+            args.resume,
             options,
         )?
     } else {
@@ -120,13 +503,23 @@ fn do_main(args: Args) -> anyhow::Result<()> {
             channel,
             paths,
             args.log_file,
-            args.output,
+            args.output.clone(),
             args.urls,
+            args.resume,
             options,
         )?
     };
 
-    if counts.output_rows == 0 {
+    if counts.cancelled {
+        write_unfinished_inputs(&args.output, &counts.unfinished_inputs)?;
+        bail!(
+            "Conversion was interrupted, {} inputs were not finished",
+            counts.unfinished_inputs.len()
+        );
+    }
+
+    // All inputs may have been converted by a previous run
+    if counts.output_rows == 0 && !args.incremental && !args.resume {
         error!("No rows written to output file. Raw stats: {counts:#?}");
         bail!("No rows written to output file");
     }
@@ -134,6 +527,30 @@ fn do_main(args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Write the inputs that were not finished to `<output>.unfinished`, one per line, so that
+/// they can be converted again by passing them on stdin.
+fn write_unfinished_inputs(output: &Path, inputs: &[PathBuf]) -> anyhow::Result<()> {
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    for input in inputs {
+        warn!("Unfinished input: {input:?}");
+    }
+    if output == Path::new("-") {
+        return Ok(());
+    }
+    let mut path = output.as_os_str().to_owned();
+    path.push(".unfinished");
+    let mut file =
+        BufWriter::new(File::create(&path).with_context(|| format!("Creating {path:?}"))?);
+    for input in inputs {
+        writeln!(file, "{}", input.display())?;
+    }
+    file.flush()?;
+    warn!("Unfinished inputs written to {path:?}");
+    Ok(())
+}
+
 fn get_paths(paths: Vec<String>) -> anyhow::Result<Vec<String>> {
     let paths = if paths.len() == 1 && paths[0] == "-" {
         info!("Reading paths from stdin");
@@ -158,17 +575,20 @@ fn run_no_progress_converter(
     log_file: Option<PathBuf>,
     output: PathBuf,
     urls: bool,
+    resume: bool,
     options: ConvertionOptions,
 ) -> anyhow::Result<ConversionCounter> {
     if urls {
         let mut converter: StandardConverter<reqwest::blocking::Response> =
             StandardConverter::new(options);
         let _guard = setup_tracing_output(log_file, None)?;
+        add_existing_sources(&mut converter, &output, resume)?;
         add_urls_to_converter(paths, &channel, &mut converter)?;
         Ok(run_converter(converter, channel, output)?)
     } else {
         let mut converter: StandardConverter<BufReader<File>> = StandardConverter::new(options);
         let _guard = setup_tracing_output(log_file, None)?;
+        add_existing_sources(&mut converter, &output, resume)?;
         add_files_to_converter(paths, &channel, &mut converter)?;
         Ok(run_converter(converter, channel, output)?)
     }
@@ -180,18 +600,21 @@ fn run_progress_converter(
     log_file: Option<PathBuf>,
     output: PathBuf,
     urls: bool,
+    resume: bool,
     options: ConvertionOptions,
 ) -> anyhow::Result<ConversionCounter> {
     if urls {
         let mut converter: ProgressBarConverter<reqwest::blocking::Response> =
             ProgressBarConverter::new(options);
         let _guard = setup_tracing_output(log_file, Some(converter.progress().clone()))?;
+        add_existing_sources(&mut converter, &output, resume)?;
         add_urls_to_converter(paths, &channel, &mut converter)?;
         Ok(run_converter(converter, channel, output)?)
     } else {
         let mut converter: ProgressBarConverter<BufReader<File>> =
             ProgressBarConverter::new(options);
         let _guard = setup_tracing_output(log_file, Some(converter.progress().clone()))?;
+        add_existing_sources(&mut converter, &output, resume)?;
         add_files_to_converter(paths, &channel, &mut converter)?;
         Ok(run_converter(converter, channel, output)?)
     }
@@ -232,6 +655,22 @@ fn add_files_to_converter(
     Ok(())
 }
 
+fn add_existing_sources<T: Read + Send>(
+    converter: &mut impl Converter<T>,
+    output: &Path,
+    resume: bool,
+) -> anyhow::Result<()> {
+    if !converter.options().incremental && !resume {
+        return Ok(());
+    }
+    let output = Output::new(output);
+    let existing = ExistingSources::read(&output, converter.options())
+        .with_context(|| format!("Reading existing output {:?}", output.path()))?;
+    info!("Found {} inputs in the existing output", existing.len());
+    converter.set_existing_sources(existing);
+    Ok(())
+}
+
 fn run_converter<T: Read + Send>(
     converter: impl Converter<T>,
     channel: RecordBatchChannel,
@@ -239,10 +678,8 @@ fn run_converter<T: Read + Send>(
 ) -> anyhow::Result<ConversionCounter> {
     info!("Options: {}", converter.options());
 
-    let output_file =
-        File::create(&output_file).with_context(|| format!("Creating file {:?}", output_file))?;
     converter
-        .convert(output_file, channel)
+        .convert(Output::new(output_file), channel)
         .context("Converting")
 }
 
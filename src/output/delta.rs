@@ -0,0 +1,702 @@
+use crate::incremental::{ExistingSources, SourceFingerprint, input_json};
+use crate::output::partition::percent_decode;
+use crate::output::{Journal, Output, OutputFileStats, PartitionBy, Table};
+use arrow::compute::{CastOptions, cast_with_options};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::errors::ParquetError;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::{Field as ParquetField, Row};
+use serde_json::{Map, Value, json};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use uuid::Uuid;
+
+const LOG_DIR: &str = "_delta_log";
+/// The file in the log naming the most recent checkpoint
+const LAST_CHECKPOINT: &str = "_last_checkpoint";
+/// The `commitInfo` field listing the inputs that were fully converted by a commit
+const CONVERTED_INPUTS: &str = "convertedInputs";
+
+/// A Delta Lake table on the local filesystem. Data files are written by a
+/// [TableWriter](crate::TableWriter) as usual, and once they are complete they are added to the
+/// table in a single commit. Each run appends a new commit, so a conversion is an atomic
+/// append to the table.
+#[derive(Debug)]
+pub struct DeltaTable {
+    path: PathBuf,
+    schema_string: String,
+    partition_columns: Vec<String>,
+    version: Option<u64>,
+}
+
+impl DeltaTable {
+    /// Open the Delta table at the output path, checking that the schema and partition columns
+    /// of any existing table match the data that will be appended to it.
+    pub fn try_open(
+        output: &Output,
+        schema: SchemaRef,
+        partition_by: Option<PartitionBy>,
+    ) -> parquet::errors::Result<Self> {
+        let schema = delta_schema(&schema);
+        let mut fields: Vec<_> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+        let partition_columns = match partition_by {
+            Some(partition_by) => {
+                // The hash prefix is not a column in the data, so it is added to the table schema
+                if partition_by.partition_column().is_none() {
+                    fields.push(Field::new(partition_by.to_string(), DataType::Utf8, false));
+                }
+                vec![partition_by.to_string()]
+            }
+            None => vec![],
+        };
+        let mut table = Self {
+            path: output.path().to_path_buf(),
+            schema_string: schema_string(&fields)?,
+            partition_columns,
+            version: None,
+        };
+        table.version = table.latest_version()?;
+        if let Some(metadata) = table.read_metadata()? {
+            let schema: Value = serde_json::from_str(&table.schema_string).map_err(json_error)?;
+            let existing_schema: Value = metadata["schemaString"]
+                .as_str()
+                .and_then(|s| serde_json::from_str(s).ok())
+                .unwrap_or_default();
+            if schema != existing_schema
+                || metadata["partitionColumns"] != json!(table.partition_columns)
+            {
+                return Err(ParquetError::General(format!(
+                    "The existing Delta table at {:?} has a different schema or partition columns",
+                    table.path
+                )));
+            }
+        }
+        Ok(table)
+    }
+
+    /// The inputs converted to the Delta table at the output path, recorded in the
+    /// `commitInfo` of each commit, and the data files that are currently part of the table,
+    /// found by replaying the `add` and `remove` actions in the log.
+    ///
+    /// Checkpoints do not contain the `commitInfo` of the commits they replace, so if those
+    /// commits have been removed the inputs they converted are not known, and are converted again.
+    pub fn existing_sources(output: &Output) -> parquet::errors::Result<ExistingSources> {
+        let table = Self {
+            path: output.path().to_path_buf(),
+            schema_string: String::new(),
+            partition_columns: vec![],
+            version: None,
+        };
+        let mut existing = ExistingSources::default();
+        let Some(latest) = table.latest_version()? else {
+            return Ok(existing);
+        };
+        let mut files = std::collections::BTreeSet::new();
+        let checkpoint = table.replay(latest, |action| {
+            if let Some(path) = action["add"]["path"].as_str() {
+                files.insert(percent_decode(path));
+            } else if let Some(path) = action["remove"]["path"].as_str() {
+                files.remove(&percent_decode(path));
+            } else if let Some(inputs) = action["commitInfo"][CONVERTED_INPUTS].as_array() {
+                for input in inputs {
+                    existing.insert_json(input);
+                }
+            }
+        })?;
+        if let Some(checkpoint) = checkpoint {
+            warn!(
+                "The Delta table at {:?} was read from checkpoint {checkpoint}, inputs converted before it will be converted again",
+                table.path
+            );
+        }
+        for file in files {
+            existing.add_file(table.path.join(file));
+        }
+        Ok(existing)
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.path.join(LOG_DIR)
+    }
+
+    fn commit_path(&self, version: u64) -> PathBuf {
+        self.log_path().join(format!("{version:020}.json"))
+    }
+
+    /// The latest version of the table, from its commits and checkpoints, or None if the table
+    /// does not exist yet.
+    fn latest_version(&self) -> parquet::errors::Result<Option<u64>> {
+        let entries = match std::fs::read_dir(self.log_path()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut latest = None;
+        for entry in entries {
+            let name = entry?.file_name();
+            let version = Path::new(&name)
+                .to_str()
+                .and_then(|name| name.split_once('.'))
+                .filter(|(stem, rest)| {
+                    stem.len() == 20 && (*rest == "json" || rest.starts_with("checkpoint."))
+                })
+                .map(|(stem, _)| stem)
+                .and_then(|stem| stem.parse::<u64>().ok());
+            latest = latest.max(version);
+        }
+        Ok(latest)
+    }
+
+    /// The most recent `metaData` action in the log.
+    fn read_metadata(&self) -> parquet::errors::Result<Option<Value>> {
+        let Some(latest) = self.version else {
+            return Ok(None);
+        };
+        let mut metadata = None;
+        self.replay(latest, |mut action| {
+            if action["metaData"].is_object() {
+                metadata = Some(action["metaData"].take());
+            }
+        })?;
+        Ok(metadata)
+    }
+
+    /// Replay the actions in the log up to the latest version. The log is replayed from the
+    /// first commit if it still exists, so that every `commitInfo` is seen. Otherwise older
+    /// commits have been removed after a checkpoint, and the log is replayed from the checkpoint
+    /// named by `_last_checkpoint`, whose version is returned.
+    fn replay(
+        &self,
+        latest: u64,
+        mut f: impl FnMut(Value),
+    ) -> parquet::errors::Result<Option<u64>> {
+        let mut checkpoint = None;
+        if !self.commit_path(0).exists() {
+            let version = self.read_checkpoint(&mut f)?.ok_or_else(|| {
+                ParquetError::General(format!(
+                    "The first commit of the Delta table at {:?} is missing, and there is no checkpoint to read instead",
+                    self.path
+                ))
+            })?;
+            checkpoint = Some(version);
+        }
+        let start = checkpoint.map_or(0, |v| v + 1);
+        for version in start..=latest {
+            let path = self.commit_path(version);
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(ParquetError::General(format!(
+                        "The Delta table at {:?} is missing the commit {path:?}",
+                        self.path
+                    )));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            for line in BufReader::new(file).lines() {
+                f(serde_json::from_str(&line?).map_err(json_error)?);
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    /// Read the actions in the checkpoint named by `_last_checkpoint`, returning the version of
+    /// the checkpoint, or None if there is no checkpoint. Each row of a checkpoint holds one
+    /// action, with the other action columns null.
+    fn read_checkpoint(&self, mut f: impl FnMut(Value)) -> parquet::errors::Result<Option<u64>> {
+        let last_checkpoint = match std::fs::read(self.log_path().join(LAST_CHECKPOINT)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let last_checkpoint: Value =
+            serde_json::from_slice(&last_checkpoint).map_err(json_error)?;
+        let version = last_checkpoint["version"].as_u64().ok_or_else(|| {
+            ParquetError::General(format!(
+                "The {LAST_CHECKPOINT} file of the Delta table at {:?} has no version",
+                self.path
+            ))
+        })?;
+        let paths: Vec<_> = match last_checkpoint["parts"].as_u64() {
+            Some(parts) => (1..=parts)
+                .map(|part| format!("{version:020}.checkpoint.{part:010}.{parts:010}.parquet"))
+                .collect(),
+            None => vec![format!("{version:020}.checkpoint.parquet")],
+        };
+        for path in paths {
+            let path = self.log_path().join(path);
+            let file = File::open(&path).map_err(|e| {
+                ParquetError::General(format!("Could not open the checkpoint {path:?}: {e}"))
+            })?;
+            let reader = SerializedFileReader::new(file)?;
+            for row in reader.get_row_iter(None)? {
+                f(row_json(&row?));
+            }
+        }
+        Ok(Some(version))
+    }
+
+    /// Commit the data files of the [Table::Files] table to the Delta table, along with the
+    /// inputs that were fully converted to them, returning the version of the new commit. The
+    /// data files are written with their in-progress names, and are moved into place first.
+    /// Nothing is committed to an existing table if there are no data files or inputs.
+    pub fn commit(
+        &self,
+        files: &[OutputFileStats],
+        inputs: &[(PathBuf, SourceFingerprint)],
+    ) -> parquet::errors::Result<Option<u64>> {
+        let now = timestamp_millis(SystemTime::now());
+        let mut actions = vec![];
+        for file in files.iter().filter(|f| f.table == Table::Files) {
+            Journal::complete(&file.path)?;
+            actions.push(self.add_action(file)?);
+        }
+        if actions.is_empty() && inputs.is_empty() && self.version.is_some() {
+            return Ok(None);
+        }
+        let inputs: Vec<_> = inputs
+            .iter()
+            .map(|(source, fingerprint)| input_json(source, fingerprint))
+            .collect();
+        actions.push(json!({"commitInfo": {
+            "timestamp": now,
+            "operation": "WRITE",
+            "operationParameters": {
+                "mode": "Append",
+                "partitionBy": serde_json::to_string(&self.partition_columns).map_err(json_error)?,
+            },
+            "engineInfo": concat!("archive-to-parquet/", env!("CARGO_PKG_VERSION")),
+            "isBlindAppend": true,
+            CONVERTED_INPUTS: inputs,
+        }}));
+
+        std::fs::create_dir_all(self.log_path())?;
+        let mut version = self.version.map_or(0, |v| v + 1);
+        loop {
+            let mut commit = vec![];
+            if version == 0 {
+                commit.push(json!({"protocol": {"minReaderVersion": 1, "minWriterVersion": 2}}));
+                commit.push(json!({"metaData": {
+                    "id": Uuid::new_v4().to_string(),
+                    "format": {"provider": "parquet", "options": {}},
+                    "schemaString": self.schema_string,
+                    "partitionColumns": self.partition_columns,
+                    "configuration": {},
+                    "createdTime": now,
+                }}));
+            }
+            commit.extend(actions.iter().cloned());
+            if self.try_write_commit(version, &commit)? {
+                return Ok(Some(version));
+            }
+            // Another writer committed this version first. Appends never conflict, so retry
+            // with the next version.
+            version += 1;
+        }
+    }
+
+    /// Atomically write a commit, returning false if the version already exists. The commit is
+    /// written to a temporary file and then hard linked into place, which fails if the
+    /// destination exists.
+    fn try_write_commit(&self, version: u64, actions: &[Value]) -> parquet::errors::Result<bool> {
+        let tmp_path = self
+            .log_path()
+            .join(format!(".{version:020}.json.{}.tmp", Uuid::new_v4()));
+        let mut tmp = File::create_new(&tmp_path)?;
+        for action in actions {
+            serde_json::to_writer(&mut tmp, action).map_err(json_error)?;
+            tmp.write_all(b"\n")?;
+        }
+        tmp.sync_all()?;
+        drop(tmp);
+        let result = std::fs::hard_link(&tmp_path, self.commit_path(version));
+        std::fs::remove_file(&tmp_path)?;
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn add_action(&self, file: &OutputFileStats) -> parquet::errors::Result<Value> {
+        let relative_path = file.path.strip_prefix(&self.path).map_err(|_| {
+            ParquetError::General(format!(
+                "Data file {:?} is not inside the Delta table {:?}",
+                file.path, self.path
+            ))
+        })?;
+        // The partition value is taken from the file, as long values are cut short in the
+        // directory name. An empty value is the null partition.
+        let mut partition_values = Map::new();
+        if let (Some(column), Some(value)) = (self.partition_columns.first(), &file.partition) {
+            let value = Some(value).filter(|value| !value.is_empty());
+            partition_values.insert(column.clone(), json!(value));
+        }
+        let metadata = std::fs::metadata(&file.path)?;
+        let stats = json!({"numRecords": file.rows}).to_string();
+        Ok(json!({"add": {
+            "path": escape_uri_path(&relative_path.to_string_lossy()),
+            "partitionValues": partition_values,
+            "size": metadata.len(),
+            "modificationTime": timestamp_millis(metadata.modified()?),
+            "dataChange": true,
+            "stats": stats,
+        }}))
+    }
+}
+
+/// Delta has no unsigned integer types, so unsigned columns are written as signed integers.
+pub(crate) fn delta_schema(schema: &SchemaRef) -> SchemaRef {
+    let fields: Vec<_> = schema
+        .fields()
+        .iter()
+        .map(|field| match field.data_type() {
+            DataType::UInt8 | DataType::UInt16 | DataType::UInt32 | DataType::UInt64 => {
+                Arc::new(field.as_ref().clone().with_data_type(DataType::Int64))
+            }
+            _ => field.clone(),
+        })
+        .collect();
+    Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()))
+}
+
+/// Cast a batch to the [delta_schema] of its schema. Values that do not fit are an error.
+pub(crate) fn cast_to_delta(
+    batch: &RecordBatch,
+    schema: &SchemaRef,
+) -> Result<RecordBatch, ArrowError> {
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| cast_with_options(column, field.data_type(), &options))
+        .collect::<Result<_, _>>()?;
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+/// The Delta schema of the table, serialized as JSON.
+fn schema_string(fields: &[Field]) -> parquet::errors::Result<String> {
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let data_type = delta_type(field.data_type()).ok_or_else(|| {
+                ParquetError::General(format!(
+                    "Column {} has a type that is not supported by Delta: {}",
+                    field.name(),
+                    field.data_type()
+                ))
+            })?;
+            Ok(json!({
+                "name": field.name(),
+                "type": data_type,
+                "nullable": field.is_nullable(),
+                "metadata": {},
+            }))
+        })
+        .collect::<parquet::errors::Result<Vec<_>>>()?;
+    Ok(json!({"type": "struct", "fields": fields}).to_string())
+}
+
+fn delta_type(data_type: &DataType) -> Option<Value> {
+    let name = match data_type {
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => "string",
+        DataType::Int64 => "long",
+        DataType::Boolean => "boolean",
+        DataType::Timestamp(_, Some(_)) => "timestamp",
+        DataType::Binary
+        | DataType::LargeBinary
+        | DataType::BinaryView
+        | DataType::FixedSizeBinary(_) => "binary",
+        DataType::List(item) | DataType::LargeList(item) => {
+            return Some(json!({
+                "type": "array",
+                "elementType": delta_type(item.data_type())?,
+                "containsNull": item.is_nullable(),
+            }));
+        }
+        _ => return None,
+    };
+    Some(json!(name))
+}
+
+/// Convert a row of a checkpoint to JSON, in the same shape as the action in a commit.
+fn row_json(row: &Row) -> Value {
+    Value::Object(
+        row.get_column_iter()
+            .map(|(name, field)| (name.clone(), field_json(field)))
+            .collect(),
+    )
+}
+
+fn field_json(field: &ParquetField) -> Value {
+    match field {
+        ParquetField::Bool(value) => json!(value),
+        ParquetField::Int(value) => json!(value),
+        ParquetField::Long(value) => json!(value),
+        ParquetField::Str(value) => json!(value),
+        ParquetField::Group(row) => row_json(row),
+        ParquetField::ListInternal(list) => list.elements().iter().map(field_json).collect(),
+        ParquetField::MapInternal(map) => map
+            .entries()
+            .iter()
+            .filter_map(|(key, value)| match key {
+                ParquetField::Str(key) => Some((key.clone(), field_json(value))),
+                _ => None,
+            })
+            .collect(),
+        // Other types are not needed to replay the log
+        _ => Value::Null,
+    }
+}
+
+/// Paths in the Delta log are relative URIs, so any reserved characters must be percent-encoded.
+fn escape_uri_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'/' | b'.' | b'-' | b'_' | b'~' | b'=') {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+fn timestamp_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn json_error(e: serde_json::Error) -> ParquetError {
+    ParquetError::External(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::incremental::SourceFingerprint;
+    use crate::output::delta::{DeltaTable, cast_to_delta, delta_schema, escape_uri_path};
+    use crate::output::{Output, OutputFileStats, PartitionBy, Table};
+    use arrow::array::{
+        Array, ArrayRef, AsArray, ListBuilder, RecordBatch, StringArray, StringBuilder,
+        StructArray, UInt64Array,
+    };
+    use arrow::buffer::NullBuffer;
+    use arrow::datatypes::{DataType, Field, Fields, Int64Type, Schema};
+    use parquet::arrow::ArrowWriter;
+    use serde_json::Value;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_escape_uri_path() {
+        assert_eq!(
+            escape_uri_path("source=%2Fimage.tar/part-00000.parquet"),
+            "source=%252Fimage.tar/part-00000.parquet"
+        );
+    }
+
+    #[test]
+    fn test_commit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("path", DataType::Utf8View, false),
+            Field::new("size", DataType::UInt64, false),
+        ]));
+        let data_file = dir.join("hash_prefix=ab/part-00000.parquet");
+        std::fs::create_dir_all(data_file.parent().unwrap()).unwrap();
+        std::fs::write(&data_file, b"data").unwrap();
+        let files = [OutputFileStats {
+            table: Table::Files,
+            path: data_file,
+            rows: 3,
+            bytes: 4,
+            partition: Some("ab".to_string()),
+        }];
+
+        let fingerprint = SourceFingerprint {
+            size: 10,
+            modified: Some(1000),
+        };
+        let inputs = [(PathBuf::from("input.tar"), fingerprint)];
+
+        for expected_version in 0..2 {
+            let table =
+                DeltaTable::try_open(&output, schema.clone(), Some(PartitionBy::HashPrefix))
+                    .unwrap();
+            assert_eq!(
+                table.commit(&files, &inputs).unwrap(),
+                Some(expected_version)
+            );
+        }
+        let existing = DeltaTable::existing_sources(&output).unwrap();
+        assert!(existing.contains("input.tar", &fingerprint));
+        assert_eq!(existing.files(), [files[0].path.clone()]);
+
+        let read_actions = |version: u64| -> Vec<Value> {
+            let path = dir.join(format!("_delta_log/{version:020}.json"));
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let first = read_actions(0);
+        assert!(
+            first[1]["metaData"]["schemaString"]
+                .as_str()
+                .unwrap()
+                .contains("hash_prefix")
+        );
+        // Delta has no unsigned types, so the size is declared as a signed long
+        assert!(
+            first[1]["metaData"]["schemaString"]
+                .as_str()
+                .unwrap()
+                .contains(r#"{"metadata":{},"name":"size","nullable":false,"type":"long"}"#)
+        );
+        assert_eq!(first[2]["add"]["partitionValues"]["hash_prefix"], "ab");
+        assert_eq!(first[2]["add"]["size"], 4);
+        let second = read_actions(1);
+        assert!(second.iter().all(|a| a.get("metaData").is_none()));
+
+        // Nothing is committed if there are no new files
+        let table =
+            DeltaTable::try_open(&output, schema.clone(), Some(PartitionBy::HashPrefix)).unwrap();
+        assert_eq!(table.commit(&[], &[]).unwrap(), None);
+
+        // Appending with a different partitioning is an error
+        assert!(DeltaTable::try_open(&output, schema, None).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let schema = Arc::new(Schema::new(vec![Field::new("path", DataType::Utf8, false)]));
+        let data_file = dir.join("hash_prefix=ab/part-00000.parquet");
+        std::fs::create_dir_all(data_file.parent().unwrap()).unwrap();
+        std::fs::write(&data_file, b"data").unwrap();
+        let files = [OutputFileStats {
+            table: Table::Files,
+            path: data_file.clone(),
+            rows: 1,
+            bytes: 4,
+            partition: Some("ab".to_string()),
+        }];
+        let fingerprint = SourceFingerprint {
+            size: 10,
+            modified: None,
+        };
+        let inputs = [(PathBuf::from("input.tar"), fingerprint)];
+        for _ in 0..2 {
+            let table =
+                DeltaTable::try_open(&output, schema.clone(), Some(PartitionBy::HashPrefix))
+                    .unwrap();
+            table.commit(&files, &inputs).unwrap();
+        }
+        let log = dir.join("_delta_log");
+        let first: Vec<Value> = std::fs::read_to_string(log.join(format!("{:020}.json", 0)))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let metadata = &first[1]["metaData"];
+
+        // A checkpoint of version 1, with a row for the metadata and a row for the data file
+        let add_fields = Fields::from(vec![Field::new("path", DataType::Utf8, true)]);
+        let add = StructArray::new(
+            add_fields.clone(),
+            vec![Arc::new(StringArray::from(vec![
+                None,
+                Some("hash_prefix=ab/part-00000.parquet"),
+            ]))],
+            Some(NullBuffer::from(vec![false, true])),
+        );
+        let mut partition_columns = ListBuilder::new(StringBuilder::new());
+        partition_columns.append_value([Some("hash_prefix")]);
+        partition_columns.append_null();
+        let partition_columns = partition_columns.finish();
+        let metadata_fields = Fields::from(vec![
+            Field::new("schemaString", DataType::Utf8, true),
+            Field::new(
+                "partitionColumns",
+                partition_columns.data_type().clone(),
+                true,
+            ),
+        ]);
+        let metadata = StructArray::new(
+            metadata_fields.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    metadata["schemaString"].as_str(),
+                    None,
+                ])),
+                Arc::new(partition_columns),
+            ],
+            Some(NullBuffer::from(vec![true, false])),
+        );
+        let checkpoint = RecordBatch::try_from_iter([
+            ("add", Arc::new(add) as ArrayRef),
+            ("metaData", Arc::new(metadata) as ArrayRef),
+        ])
+        .unwrap();
+        let file =
+            std::fs::File::create(log.join(format!("{:020}.checkpoint.parquet", 1))).unwrap();
+        let mut writer = ArrowWriter::try_new(file, checkpoint.schema(), None).unwrap();
+        writer.write(&checkpoint).unwrap();
+        writer.close().unwrap();
+        std::fs::remove_file(log.join(format!("{:020}.json", 0))).unwrap();
+
+        // Without _last_checkpoint the missing first commit is an error
+        assert!(DeltaTable::existing_sources(&output).is_err());
+
+        std::fs::write(log.join("_last_checkpoint"), r#"{"version":1,"size":2}"#).unwrap();
+        let existing = DeltaTable::existing_sources(&output).unwrap();
+        assert_eq!(existing.files(), [data_file]);
+        // The commit info of the commits replaced by the checkpoint is not known
+        assert!(!existing.contains("input.tar", &fingerprint));
+
+        // The metadata is read from the checkpoint
+        assert!(DeltaTable::try_open(&output, schema.clone(), None).is_err());
+        let table = DeltaTable::try_open(&output, schema, Some(PartitionBy::HashPrefix)).unwrap();
+        assert_eq!(table.commit(&files, &inputs).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_unsigned_columns() {
+        let batch = RecordBatch::try_from_iter([(
+            "size",
+            Arc::new(UInt64Array::from(vec![0, 1, i64::MAX as u64])) as ArrayRef,
+        )])
+        .unwrap();
+        let schema = delta_schema(&batch.schema());
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+        let cast = cast_to_delta(&batch, &schema).unwrap();
+        assert_eq!(
+            cast.column(0).as_primitive::<Int64Type>().values(),
+            &[0, 1, i64::MAX]
+        );
+
+        // Sizes that do not fit are an error rather than null
+        let batch = RecordBatch::try_from_iter([(
+            "size",
+            Arc::new(UInt64Array::from(vec![u64::MAX])) as ArrayRef,
+        )])
+        .unwrap();
+        assert!(cast_to_delta(&batch, &schema).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::{ConvertionOptions, new_parquet_writer_with_options};
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::ipc::reader::{FileReader, StreamReader};
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+use arrow::record_batch::{RecordBatch, RecordBatchIterator, RecordBatchReader};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::{ArrowWriter, ProjectionMask};
+use parquet::errors::ParquetError;
+use parquet::file::metadata::KeyValue;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The file format that tables are written in.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Default,
+    clap::ValueEnum,
+    strum::EnumIs,
+    strum::EnumString,
+    strum::Display,
+)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum OutputFormat {
+    /// Apache Parquet
+    #[default]
+    Parquet,
+    /// Arrow IPC file format, also known as Feather
+    Ipc,
+    /// Arrow IPC stream format. This can be read as it is written, e.g. through a pipe.
+    IpcStream,
+    /// A Delta Lake table: a directory of Parquet files and a `_delta_log` transaction log.
+    /// Each run appends a new commit to the table.
+    Delta,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Parquet | OutputFormat::Delta => "parquet",
+            OutputFormat::Ipc => "arrow",
+            OutputFormat::IpcStream => "arrows",
+        }
+    }
+
+    /// Detect the format of a file from its first bytes. Parquet and Arrow IPC files start with
+    /// `PAR1` and `ARROW1`. The IPC stream format has no magic, so a file starting with the
+    /// continuation marker of an IPC message is read as a stream.
+    pub(crate) fn detect(path: &Path) -> parquet::errors::Result<Self> {
+        let mut magic = [0; 6];
+        let read = File::open(path)?.take(6).read(&mut magic)?;
+        let magic = &magic[..read];
+        if magic.starts_with(b"PAR1") {
+            Ok(OutputFormat::Parquet)
+        } else if magic.starts_with(b"ARROW1") {
+            Ok(OutputFormat::Ipc)
+        } else if magic.starts_with(&[0xff; 4]) {
+            Ok(OutputFormat::IpcStream)
+        } else {
+            Err(ParquetError::General(format!(
+                "{path:?} is not a Parquet or Arrow IPC file"
+            )))
+        }
+    }
+}
+
+pub(crate) type BoxedBatchReader = Box<dyn RecordBatchReader>;
+
+/// Read a file written in any [OutputFormat], detected from the first bytes of the file. Returns the
+/// key-value metadata of the file, and a reader of the given columns, or of every column if
+/// `columns` is None. Columns that are not in the file are skipped.
+pub(crate) fn read_file(
+    path: &Path,
+    columns: Option<&[&str]>,
+) -> parquet::errors::Result<(Vec<(String, String)>, BoxedBatchReader)> {
+    let open = || File::open(path).map(BufReader::new);
+    let projection = |schema: &Schema| -> Option<Vec<usize>> {
+        columns.map(|columns| {
+            columns
+                .iter()
+                .filter_map(|name| schema.index_of(name).ok())
+                .collect()
+        })
+    };
+    match OutputFormat::detect(path)? {
+        OutputFormat::Parquet | OutputFormat::Delta => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+            let metadata = builder
+                .metadata()
+                .file_metadata()
+                .key_value_metadata()
+                .into_iter()
+                .flatten()
+                .map(|kv| (kv.key.clone(), kv.value.clone().unwrap_or_default()))
+                .collect();
+            let schema_metadata = builder.schema().metadata().clone();
+            let builder = match projection(builder.schema()) {
+                Some(columns) => {
+                    let mask = ProjectionMask::roots(builder.parquet_schema(), columns);
+                    builder.with_projection(mask)
+                }
+                None => builder,
+            };
+            // The Parquet reader drops the metadata of the Arrow schema, which the IPC readers keep
+            let reader = builder.build()?;
+            let schema = Arc::new(
+                reader
+                    .schema()
+                    .as_ref()
+                    .clone()
+                    .with_metadata(schema_metadata),
+            );
+            let batches = {
+                let schema = schema.clone();
+                reader.map(move |batch| batch?.with_schema(schema.clone()))
+            };
+            Ok((
+                metadata,
+                Box::new(RecordBatchIterator::new(batches, schema)),
+            ))
+        }
+        OutputFormat::Ipc => {
+            let reader = FileReader::try_new(open()?, None)?;
+            let metadata = reader
+                .custom_metadata()
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            let columns = projection(&reader.schema());
+            Ok((metadata, Box::new(FileReader::try_new(open()?, columns)?)))
+        }
+        OutputFormat::IpcStream => {
+            let columns = projection(&StreamReader::try_new(open()?, None)?.schema());
+            Ok((vec![], Box::new(StreamReader::try_new(open()?, columns)?)))
+        }
+    }
+}
+
+/// Counts the number of bytes written to the inner writer.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer for any [OutputFormat]
+pub enum FormatWriter<W: Write + Send> {
+    Parquet(ArrowWriter<CountingWriter<W>>),
+    Ipc(FileWriter<CountingWriter<W>>, u64),
+    IpcStream(StreamWriter<CountingWriter<W>>, u64),
+}
+
+impl<W: Write + Send> FormatWriter<W> {
+    pub fn try_new(
+        writer: W,
+        schema: SchemaRef,
+        options: &ConvertionOptions,
+    ) -> parquet::errors::Result<Self> {
+        let counting = |inner| CountingWriter { inner, written: 0 };
+        Ok(match options.output_format {
+            OutputFormat::Parquet | OutputFormat::Delta => Self::Parquet(
+                new_parquet_writer_with_options(counting(writer), schema, options)?,
+            ),
+            OutputFormat::Ipc => Self::Ipc(FileWriter::try_new(counting(writer), &schema)?, 0),
+            OutputFormat::IpcStream => {
+                Self::IpcStream(StreamWriter::try_new(counting(writer), &schema)?, 0)
+            }
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> parquet::errors::Result<()> {
+        match self {
+            FormatWriter::Parquet(writer) => writer.write(batch)?,
+            FormatWriter::Ipc(writer, rows) => {
+                writer.write(batch)?;
+                *rows += batch.num_rows() as u64;
+            }
+            FormatWriter::IpcStream(writer, rows) => {
+                writer.write(batch)?;
+                *rows += batch.num_rows() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of rows written, including any buffered rows
+    pub fn rows(&self) -> u64 {
+        match self {
+            FormatWriter::Parquet(writer) => {
+                let flushed_rows: i64 = writer
+                    .flushed_row_groups()
+                    .iter()
+                    .map(|rg| rg.num_rows())
+                    .sum();
+                flushed_rows as u64 + writer.in_progress_rows() as u64
+            }
+            FormatWriter::Ipc(_, rows) | FormatWriter::IpcStream(_, rows) => *rows,
+        }
+    }
+
+    /// The (estimated) size of the file, including any buffered rows
+    pub fn size(&self) -> u64 {
+        match self {
+            FormatWriter::Parquet(writer) => {
+                (writer.bytes_written() + writer.in_progress_size()) as u64
+            }
+            FormatWriter::Ipc(writer, _) => writer.get_ref().written,
+            FormatWriter::IpcStream(writer, _) => writer.get_ref().written,
+        }
+    }
+
+    /// The memory used to buffer rows that have not been written yet
+    pub fn memory_size(&self) -> usize {
+        match self {
+            FormatWriter::Parquet(writer) => writer.memory_size(),
+            FormatWriter::Ipc(..) | FormatWriter::IpcStream(..) => 0,
+        }
+    }
+
+    pub fn flush(&mut self) -> parquet::errors::Result<()> {
+        match self {
+            FormatWriter::Parquet(writer) => writer.flush()?,
+            FormatWriter::Ipc(writer, _) => writer.flush()?,
+            FormatWriter::IpcStream(writer, _) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Finish writing the file with the given key-value metadata, returning the number of rows
+    /// and bytes written. The IPC stream format has no footer, so the metadata is not written.
+    pub fn finish(&mut self, metadata: &[(String, String)]) -> parquet::errors::Result<(u64, u64)> {
+        match self {
+            FormatWriter::Parquet(writer) => {
+                for (key, value) in metadata {
+                    writer.append_key_value_metadata(KeyValue::new(key.clone(), value.clone()));
+                }
+                writer.flush()?;
+                let metadata = writer.finish()?;
+                Ok((
+                    metadata.file_metadata().num_rows() as u64,
+                    writer.inner().written,
+                ))
+            }
+            FormatWriter::Ipc(writer, rows) => {
+                for (key, value) in metadata {
+                    writer.write_metadata(key, value);
+                }
+                writer.finish()?;
+                writer.flush()?;
+                Ok((*rows, writer.get_ref().written))
+            }
+            FormatWriter::IpcStream(writer, rows) => {
+                writer.finish()?;
+                writer.flush()?;
+                Ok((*rows, writer.get_ref().written))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ConvertionOptions;
+    use crate::output::format::read_file;
+    use crate::output::{FormatWriter, OutputFormat};
+    use arrow::array::{ArrayRef, RecordBatch, StringViewArray, UInt64Array};
+    use arrow::ipc::reader::{FileReader, StreamReader};
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn batch(paths: &[&str]) -> RecordBatch {
+        RecordBatch::try_from_iter([
+            (
+                "path",
+                Arc::new(StringViewArray::from_iter_values(paths)) as ArrayRef,
+            ),
+            (
+                "size",
+                Arc::new(UInt64Array::from_iter_values(
+                    paths.iter().map(|path| path.len() as u64),
+                )),
+            ),
+        ])
+        .unwrap()
+    }
+
+    /// Write the batches in the given format to a file in `dir`, returning the path of the file
+    fn write(dir: &Path, format: OutputFormat, batches: &[RecordBatch]) -> PathBuf {
+        let path = dir.join(format!("output.{}", format.extension()));
+        let mut options = ConvertionOptions::const_default();
+        options.output_format = format;
+        let mut writer =
+            FormatWriter::try_new(File::create(&path).unwrap(), batches[0].schema(), &options)
+                .unwrap();
+        for batch in batches {
+            writer.write(batch).unwrap();
+        }
+        assert_eq!(writer.rows(), 3);
+        let metadata = [("key".to_string(), "value".to_string())];
+        let (rows, bytes) = writer.finish(&metadata).unwrap();
+        assert_eq!(rows, 3);
+        assert_eq!(bytes, std::fs::metadata(&path).unwrap().len());
+        path
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let batches = [batch(&["a", "b/c"]), batch(&["d/e/f"])];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = write(tmp.path(), OutputFormat::Parquet, &batches);
+        let (metadata, reader) = read_file(&path, None).unwrap();
+        assert!(metadata.contains(&("key".to_string(), "value".to_string())));
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let batches = [batch(&["a", "b/c"]), batch(&["d/e/f"])];
+        for format in [
+            OutputFormat::Parquet,
+            OutputFormat::Ipc,
+            OutputFormat::IpcStream,
+        ] {
+            // The format is detected from the content, not the extension
+            let tmp = tempfile::tempdir().unwrap();
+            let path = write(tmp.path(), format, &batches);
+            let renamed = path.with_extension("bin");
+            std::fs::rename(&path, &renamed).unwrap();
+            assert_eq!(OutputFormat::detect(&renamed).unwrap(), format);
+            let (_, reader) = read_file(&renamed, None).unwrap();
+            let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+            assert_eq!(rows, 3);
+        }
+    }
+
+    #[test]
+    fn test_ipc_round_trip() {
+        let batches = [batch(&["a", "b/c"]), batch(&["d/e/f"])];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = write(tmp.path(), OutputFormat::Ipc, &batches);
+        let reader = FileReader::try_new(File::open(&path).unwrap(), None).unwrap();
+        assert_eq!(reader.custom_metadata()["key"], "value");
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, batches);
+    }
+
+    #[test]
+    fn test_ipc_stream_round_trip() {
+        let batches = [batch(&["a", "b/c"]), batch(&["d/e/f"])];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = write(tmp.path(), OutputFormat::IpcStream, &batches);
+        let reader = StreamReader::try_new(File::open(&path).unwrap(), None).unwrap();
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, batches);
+    }
+}
@@ -0,0 +1,501 @@
+use crate::ConvertionOptions;
+use crate::incremental::{ExistingSources, SourceFingerprint, input_json};
+use crate::output::partition::escape_partition_value;
+use crate::output::{FormatWriter, Output, OutputFileStats, PartitionBy, read_file};
+use arrow::array::{AsArray, BooleanArray};
+use arrow::compute::{cast, filter_record_batch};
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+use parquet::errors::ParquetError;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+const JOURNAL_FILE: &str = "_journal.jsonl";
+const IN_PROGRESS_SUFFIX: &str = ".inprogress";
+
+/// A journal of checkpoints written to an output dataset, recording which inputs have been
+/// converted, so that a conversion that is interrupted can be resumed and an incremental
+/// conversion knows which inputs to skip.
+///
+/// Files are written with a hidden in-progress name. At each checkpoint, and when the
+/// conversion finishes, every open file is finished and a line listing the finished files and
+/// every input that has been fully written to them is appended to the journal. The files are
+/// then renamed into place. Each line also lists the inputs that have rows in its files but
+/// were not finished yet.
+///
+/// When recovering, any files listed in the journal that were not renamed are renamed, and the
+/// remaining in-progress files are deleted. The rows of inputs that were partly written but
+/// never finished are removed from the files, so that converting them again does not
+/// duplicate their rows, and the inputs listed in the journal are skipped.
+#[derive(Debug)]
+pub struct Journal {
+    output: Output,
+}
+
+impl Journal {
+    pub fn new(output: &Output) -> Self {
+        Self {
+            output: output.clone(),
+        }
+    }
+
+    fn journal_path(output: &Output) -> PathBuf {
+        output.path().join(JOURNAL_FILE)
+    }
+
+    pub fn exists(output: &Output) -> bool {
+        Self::journal_path(output).exists()
+    }
+
+    /// The name that a file is written to until it is checkpointed, e.g.
+    /// `.part-00000.parquet.inprogress`.
+    pub fn in_progress_path(path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{name}{IN_PROGRESS_SUFFIX}"))
+    }
+
+    /// Record a checkpoint, then move the finished files into place. `partial` lists the inputs
+    /// with rows in the files that have not been finished.
+    pub fn checkpoint(
+        &self,
+        files: &[OutputFileStats],
+        inputs: &[(PathBuf, SourceFingerprint)],
+        partial: &[String],
+    ) -> parquet::errors::Result<()> {
+        let files: Vec<_> = files
+            .iter()
+            .map(|file| self.relative_path(&file.path))
+            .collect::<parquet::errors::Result<_>>()?;
+        let inputs: Vec<_> = inputs
+            .iter()
+            .map(|(source, fingerprint)| input_json(source, fingerprint))
+            .collect();
+        let line = json!({"files": files, "inputs": inputs, "partial": partial}).to_string();
+
+        std::fs::create_dir_all(self.output.path())?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::journal_path(&self.output))?;
+        writeln!(journal, "{line}")?;
+        journal.sync_all()?;
+
+        for file in &files {
+            self.complete_file(file)?;
+        }
+        debug!(
+            "Checkpointed {} files and {} inputs",
+            files.len(),
+            inputs.len()
+        );
+        Ok(())
+    }
+
+    fn relative_path(&self, path: &Path) -> parquet::errors::Result<String> {
+        path.strip_prefix(self.output.path())
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|_| {
+                ParquetError::General(format!("{path:?} is not inside {:?}", self.output.path()))
+            })
+    }
+
+    fn complete_file(&self, relative_path: &str) -> std::io::Result<()> {
+        Self::complete(&self.output.path().join(relative_path))
+    }
+
+    /// Move a file that was written with its in-progress name into place, if it has not been
+    /// already.
+    pub(crate) fn complete(path: &Path) -> std::io::Result<()> {
+        let in_progress = Self::in_progress_path(path);
+        if in_progress.exists() {
+            std::fs::rename(&in_progress, path)?;
+        }
+        Ok(())
+    }
+
+    /// Recover the output after an interrupted conversion, returning the inputs that have
+    /// already been fully converted and the files they were written to.
+    pub fn recover(
+        output: &Output,
+        options: &ConvertionOptions,
+    ) -> parquet::errors::Result<ExistingSources> {
+        let journal = Self::new(output);
+        let mut sources = ExistingSources::default();
+        let contents = match std::fs::read_to_string(Self::journal_path(output)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(sources),
+            Err(e) => return Err(e.into()),
+        };
+        let mut checkpoints = vec![];
+        let mut valid_length = 0;
+        for line in contents.split_inclusive('\n') {
+            // The last line may be incomplete, in which case the checkpoint never happened
+            let checkpoint = match serde_json::from_str::<Value>(line) {
+                Ok(checkpoint) if line.ends_with('\n') => checkpoint,
+                _ => break,
+            };
+            valid_length += line.len();
+            for file in strings(&checkpoint["files"]) {
+                journal.complete_file(file)?;
+            }
+            checkpoints.push(checkpoint);
+        }
+        if valid_length < contents.len() {
+            // Remove the incomplete checkpoint, so that new checkpoints can be appended
+            OpenOptions::new()
+                .write(true)
+                .open(Self::journal_path(output))?
+                .set_len(valid_length as u64)?;
+        }
+        journal.remove_in_progress_files()?;
+        if journal.remove_partial_inputs(&mut checkpoints, options)? {
+            journal.rewrite(&checkpoints)?;
+        }
+
+        for checkpoint in &checkpoints {
+            for file in strings(&checkpoint["files"]) {
+                let path = output.path().join(file);
+                // Files that only contained rows of unfinished inputs have been removed
+                if path.exists() {
+                    sources.add_file(path);
+                }
+            }
+            for input in checkpoint["inputs"].as_array().into_iter().flatten() {
+                sources.insert_json(input);
+            }
+        }
+        info!(
+            "Recovered the journal in {:?}, {} inputs already converted",
+            output.path(),
+            sources.len()
+        );
+        Ok(sources)
+    }
+
+    /// Remove the rows of inputs that were partly written but never finished from the files of
+    /// each checkpoint. An input that is finished by a later checkpoint was written in full, so
+    /// its rows are kept. Returns true if any checkpoint listed partial inputs, which are then
+    /// removed from the checkpoints as they have been dealt with.
+    fn remove_partial_inputs(
+        &self,
+        checkpoints: &mut [Value],
+        options: &ConvertionOptions,
+    ) -> parquet::errors::Result<bool> {
+        let mut finished_after = HashSet::new();
+        let mut any_partial = false;
+        for checkpoint in checkpoints.iter_mut().rev() {
+            let unfinished: HashSet<_> = strings(&checkpoint["partial"])
+                .filter(|source| !finished_after.contains(*source))
+                .map(str::to_string)
+                .collect();
+            if !unfinished.is_empty() {
+                info!(
+                    "Removing the rows of {} unfinished inputs from the output",
+                    unfinished.len()
+                );
+                for file in strings(&checkpoint["files"]) {
+                    let path = self.output.path().join(file);
+                    remove_sources(&self.output, &path, &unfinished, options)?;
+                }
+            }
+            if let Some(checkpoint) = checkpoint.as_object_mut() {
+                any_partial |= checkpoint
+                    .remove("partial")
+                    .is_some_and(|partial| partial.as_array().is_some_and(|p| !p.is_empty()));
+            }
+            for input in checkpoint["inputs"].as_array().into_iter().flatten() {
+                finished_after.insert(input["source"].as_str().unwrap_or_default().to_string());
+            }
+        }
+        Ok(any_partial)
+    }
+
+    /// Replace the journal with the given checkpoints
+    fn rewrite(&self, checkpoints: &[Value]) -> parquet::errors::Result<()> {
+        let path = Self::journal_path(&self.output);
+        let tmp_path = Self::in_progress_path(&path);
+        let mut tmp = File::create(&tmp_path)?;
+        for checkpoint in checkpoints {
+            writeln!(tmp, "{checkpoint}")?;
+        }
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn remove_in_progress_files(&self) -> parquet::errors::Result<()> {
+        let mut dirs = vec![self.output.path().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if entry.file_type()?.is_dir() {
+                    dirs.push(entry.path());
+                } else if name.starts_with('.') && name.ends_with(IN_PROGRESS_SUFFIX) {
+                    debug!("Removing incomplete file {:?}", entry.path());
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The strings in a JSON array, or nothing if it is not an array
+fn strings(value: &Value) -> impl Iterator<Item = &str> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str())
+}
+
+/// Remove the rows read from any of `sources` from a data file of the output. The file is
+/// written again under a temporary name and moved into place, or removed if no rows are left.
+/// Returns the number of rows left, or None if the file has no rows from the sources. Files of
+/// tables without a source, such as blobs and chunks, are left as they are.
+pub(crate) fn remove_sources(
+    output: &Output,
+    path: &Path,
+    sources: &HashSet<String>,
+    options: &ConvertionOptions,
+) -> parquet::errors::Result<Option<u64>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    // Files in a source partition do not store the source, as every row is from that source
+    let partition = match options.partition_by {
+        Some(PartitionBy::Source) => source_partition(output, path),
+        _ => None,
+    };
+    if let Some(partition) = partition {
+        // Long sources are cut short in the directory name, so the sources are escaped to match
+        if !sources
+            .iter()
+            .any(|source| escape_partition_value(source) == partition)
+        {
+            return Ok(None);
+        }
+        debug!("Removing {path:?}, it only has rows of unfinished inputs");
+        std::fs::remove_file(path)?;
+        return Ok(Some(0));
+    }
+
+    let (_, reader) = read_file(path, Some(&["source"]))?;
+    if reader.schema().index_of("source").is_err() {
+        return Ok(None);
+    }
+    let keep_rows = |batch: &RecordBatch| -> parquet::errors::Result<BooleanArray> {
+        let column = batch
+            .column_by_name("source")
+            .ok_or_else(|| ParquetError::General(format!("{path:?} has no source column")))?;
+        let column = cast(column, &DataType::Utf8)?;
+        Ok(column
+            .as_string::<i32>()
+            .iter()
+            .map(|source| Some(!sources.contains(source.unwrap_or_default())))
+            .collect())
+    };
+    let mut removed = 0;
+    for batch in reader {
+        removed += keep_rows(&batch?)?.false_count();
+    }
+    if removed == 0 {
+        return Ok(None);
+    }
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{name}.rewrite{IN_PROGRESS_SUFFIX}"));
+    let (metadata, reader) = read_file(path, None)?;
+    let mut writer = FormatWriter::try_new(File::create(&tmp_path)?, reader.schema(), options)?;
+    for batch in reader {
+        let batch = batch?;
+        writer.write(&filter_record_batch(&batch, &keep_rows(&batch)?)?)?;
+    }
+    let (rows, _) = writer.finish(&metadata)?;
+    drop(writer);
+    if rows == 0 {
+        debug!("Removing {path:?}, it only has rows of unfinished inputs");
+        std::fs::remove_file(&tmp_path)?;
+        std::fs::remove_file(path)?;
+    } else {
+        debug!("Removed {removed} rows of unfinished inputs from {path:?}");
+        std::fs::rename(&tmp_path, path)?;
+    }
+    Ok(Some(rows))
+}
+
+/// The escaped value of the `source=` partition directory that a file is in, if any.
+fn source_partition(output: &Output, path: &Path) -> Option<String> {
+    path.strip_prefix(output.path())
+        .ok()
+        .and_then(|relative| relative.parent())
+        .into_iter()
+        .flat_map(|parent| parent.iter())
+        .find_map(|component| {
+            let value = component.to_str()?.strip_prefix("source=")?;
+            Some(value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::OutputBatch;
+    use crate::dedupe::SeenHashes;
+    use crate::hash_index::HashKind;
+    use crate::incremental::SourceFingerprint;
+    use crate::output::journal::Journal;
+    use crate::output::{Output, OutputFileStats, Table, read_file};
+    use crate::{ConvertionOptions, FileEntry, ParquetSink};
+    use arrow::array::{AsArray, RecordBatch};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    /// A batch with a row for each `(source, path)`, with the path as the content
+    fn batch(options: &ConvertionOptions, rows: &[(&str, &str)]) -> RecordBatch {
+        let mut batch = OutputBatch::new_with_options(options.clone());
+        for (source, path) in rows {
+            let mut entry = FileEntry::from_bytes(path, path.to_string()).unwrap();
+            batch
+                .add_record(Path::new(source), PathBuf::new(), &mut entry)
+                .unwrap();
+        }
+        batch.create_record_batch_and_reset().unwrap()
+    }
+
+    /// Every `(source, path)` row in the files
+    fn read_rows(files: &[PathBuf]) -> Vec<(String, String)> {
+        let mut rows = vec![];
+        for file in files {
+            let (_, reader) = read_file(file, Some(&["source", "path"])).unwrap();
+            for batch in reader {
+                let batch = batch.unwrap();
+                let sources = batch.column(0).as_string_view();
+                let paths = batch.column(1).as_string_view();
+                for idx in 0..batch.num_rows() {
+                    rows.push((sources.value(idx).into(), paths.value(idx).into()));
+                }
+            }
+        }
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn test_recover() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let file = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(Journal::in_progress_path(&path), b"data").unwrap();
+            OutputFileStats {
+                table: Table::Files,
+                path,
+                rows: 1,
+                bytes: 4,
+                partition: None,
+            }
+        };
+        let fingerprint = SourceFingerprint {
+            size: 1,
+            modified: None,
+        };
+
+        let journal = Journal::new(&output);
+        journal
+            .checkpoint(
+                &[file("a.parquet")],
+                &[(PathBuf::from("a"), fingerprint)],
+                &[],
+            )
+            .unwrap();
+        assert!(dir.join("a.parquet").exists());
+        // A file written after the last checkpoint is removed when recovering, along with an
+        // incomplete checkpoint
+        file("b.parquet");
+        let mut journal_file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("_journal.jsonl"))
+            .unwrap();
+        write!(journal_file, "{{\"files\": [\"b.par").unwrap();
+
+        let options = ConvertionOptions::const_default();
+        let sources = Journal::recover(&output, &options).unwrap();
+        assert!(sources.contains("a", &fingerprint));
+        assert!(!sources.contains_source("b"));
+        assert!(!Journal::in_progress_path(&dir.join("b.parquet")).exists());
+        journal.checkpoint(&[], &[], &[]).unwrap();
+        assert_eq!(Journal::recover(&output, &options).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_resume_partial_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let mut options = ConvertionOptions::const_default();
+        options.checkpoint_interval = Some(0);
+        let fingerprint = SourceFingerprint::default();
+
+        // a.tar is finished after the first checkpoint, but the conversion is interrupted part
+        // way through b.tar, after two checkpoints and some unjournaled rows
+        let mut sink = ParquetSink::new(&output, options.clone()).unwrap();
+        sink.write_batch(batch(&options, &[("a.tar", "1"), ("b.tar", "1")]))
+            .unwrap();
+        sink.maybe_checkpoint().unwrap();
+        sink.input_finished(PathBuf::from("a.tar"), fingerprint);
+        sink.write_batch(batch(&options, &[("b.tar", "2")]))
+            .unwrap();
+        sink.maybe_checkpoint().unwrap();
+        sink.write_batch(batch(&options, &[("b.tar", "3")]))
+            .unwrap();
+        drop(sink);
+
+        // Only the rows of a.tar are left in the output
+        let existing = Journal::recover(&output, &options).unwrap();
+        assert!(existing.contains("a.tar", &fingerprint));
+        assert!(!existing.contains_source("b.tar"));
+        assert_eq!(existing.files().len(), 1);
+        assert_eq!(
+            read_rows(existing.files()),
+            vec![("a.tar".into(), "1".into())]
+        );
+        let journal = std::fs::read_to_string(dir.join("_journal.jsonl")).unwrap();
+        assert!(!journal.contains("partial"));
+
+        // The files already written are seen when resuming
+        options.unique = true;
+        let seen_hashes = SeenHashes::for_output(&options, &existing, HashKind::Files)
+            .unwrap()
+            .unwrap();
+        assert_eq!(seen_hashes.len(), 1);
+
+        // Resuming converts b.tar again from the start, without duplicating any rows
+        let mut sink = ParquetSink::new(&output, options.clone()).unwrap();
+        sink.write_batch(batch(
+            &options,
+            &[("b.tar", "1"), ("b.tar", "2"), ("b.tar", "3")],
+        ))
+        .unwrap();
+        sink.input_finished(PathBuf::from("b.tar"), fingerprint);
+        sink.finish().unwrap();
+
+        let existing = Journal::recover(&output, &options).unwrap();
+        assert_eq!(existing.len(), 2);
+        assert_eq!(
+            read_rows(existing.files()),
+            vec![
+                ("a.tar".into(), "1".into()),
+                ("b.tar".into(), "1".into()),
+                ("b.tar".into(), "2".into()),
+                ("b.tar".into(), "3".into()),
+            ]
+        );
+    }
+}
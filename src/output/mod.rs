@@ -0,0 +1,249 @@
+mod delta;
+mod format;
+mod journal;
+mod partition;
+mod sort;
+mod writer;
+
+pub use delta::DeltaTable;
+pub(crate) use format::read_file;
+pub use format::{FormatWriter, OutputFormat};
+use indicatif::{DecimalBytes, HumanCount, ProgressBar};
+pub use journal::Journal;
+pub(crate) use journal::remove_sources;
+pub use partition::PartitionBy;
+pub use sort::SortBy;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+pub use writer::TableWriter;
+
+pub type OutputWriter = Box<dyn Write + Send>;
+
+/// The tables that can be written to an [Output].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Table {
+    /// The main table, containing a row for every file.
+    Files,
+    /// The content of every unique file, keyed by hash.
+    Blobs,
+    /// The content of every unique chunk of file content, keyed by hash.
+    Chunks,
+    /// The chunks that the content of every unique file is made of.
+    FileChunks,
+    /// The secrets found in the content of every file.
+    Findings,
+}
+
+/// The location that output tables are written to. The [Table::Files] table is written to the
+/// output path itself, and any other tables are written next to it. If the table is partitioned
+/// then the output path is a directory containing a subdirectory for each partition.
+#[derive(Clone)]
+pub struct Output {
+    path: PathBuf,
+    progress: Option<ProgressBar>,
+    /// The writer given to [Output::from_writer], until the output file is created
+    writer: Option<Arc<Mutex<Option<OutputWriter>>>>,
+}
+
+impl Debug for Output {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output")
+            .field("path", &self.path)
+            .field("progress", &self.progress)
+            .field("writer", &self.writer.is_some())
+            .finish()
+    }
+}
+
+impl Output {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            progress: None,
+            writer: None,
+        }
+    }
+
+    /// An output that writes a single file to a writer, such as a buffer, socket or pipe. Like
+    /// writing to stdout, only a single table can be written, without partitions or rolling
+    /// over to new files.
+    pub fn from_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            path: PathBuf::from("-"),
+            progress: None,
+            writer: Some(Arc::new(Mutex::new(Some(Box::new(writer))))),
+        }
+    }
+
+    /// Track the number of bytes written to any output file with the given progress bar
+    pub fn with_progress_bar(mut self, progress: ProgressBar) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the file for a given table, e.g. `output.parquet` and `output.blobs.parquet`.
+    pub fn table_path(&self, table: Table) -> PathBuf {
+        self.file_path(table, None)
+    }
+
+    /// The path of a shard of a table, e.g. `output-00001.parquet` and
+    /// `output.blobs-00001.parquet`.
+    pub fn shard_path(&self, table: Table, shard: usize) -> PathBuf {
+        self.file_path(table, Some(shard))
+    }
+
+    /// The path of a shard within a partition directory, e.g.
+    /// `output/format=tar/part-00001.parquet`.
+    pub fn partition_path(&self, partition: &str, shard: usize, extension: &str) -> PathBuf {
+        self.path
+            .join(partition)
+            .join(format!("part-{shard:05}.{extension}"))
+    }
+
+    /// The path of a data file within a dataset directory, such as a Delta table, e.g.
+    /// `output/format=tar/part-00001-<id>.parquet`. The id is unique to each run, so that
+    /// appending to a dataset never overwrites existing files.
+    pub fn dataset_file_path(
+        &self,
+        partition: Option<&str>,
+        shard: usize,
+        id: &str,
+        extension: &str,
+    ) -> PathBuf {
+        let dir = match partition {
+            Some(partition) => self.path.join(partition),
+            None => self.path.clone(),
+        };
+        dir.join(format!("part-{shard:05}-{id}.{extension}"))
+    }
+
+    /// Returns true if the output is written to stdout, which is the case when the path is `-`,
+    /// or to the writer given to [Output::from_writer].
+    pub fn is_stdout(&self) -> bool {
+        self.path == Path::new("-")
+    }
+
+    fn file_path(&self, table: Table, shard: Option<usize>) -> PathBuf {
+        let mut name = self
+            .path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if table != Table::Files {
+            name.push_str(&format!(".{table}"));
+        }
+        if let Some(shard) = shard {
+            name.push_str(&format!("-{shard:05}"));
+        }
+        if let Some(ext) = self.path.extension() {
+            name.push_str(&format!(".{}", ext.to_string_lossy()));
+        }
+        self.path.with_file_name(name)
+    }
+
+    pub fn create(&self, path: &Path) -> std::io::Result<OutputWriter> {
+        if self.is_stdout() {
+            if path != self.path {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Only a single file can be written to stdout, not {path:?}"),
+                ));
+            }
+            return match &self.writer {
+                Some(writer) => writer
+                    .lock()
+                    .expect("output writer lock poisoned")
+                    .take()
+                    .ok_or_else(|| {
+                        std::io::Error::other("The output writer has already been used")
+                    }),
+                None => Ok(Box::new(BufWriter::new(std::io::stdout()))),
+            };
+        }
+        let with_context = |e: std::io::Error| {
+            std::io::Error::new(e.kind(), format!("Error creating {path:?}: {e}"))
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(with_context)?;
+        }
+        let file = File::create(path).map_err(with_context)?;
+        Ok(match &self.progress {
+            Some(progress) => Box::new(progress.wrap_write(file)),
+            None => Box::new(file),
+        })
+    }
+}
+
+/// Statistics about a single file written to an [Output]
+#[derive(Debug, Clone)]
+pub struct OutputFileStats {
+    pub table: Table,
+    pub path: PathBuf,
+    pub rows: u64,
+    pub bytes: u64,
+    /// The value of the partition that the file is in, if the table is partitioned. Long
+    /// values are cut short in the directory name, so this is the only full copy of the value.
+    pub partition: Option<String>,
+}
+
+impl Display for OutputFileStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}): rows={} size={}",
+            self.path.display(),
+            self.table,
+            HumanCount(self.rows),
+            DecimalBytes(self.bytes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::{Output, Table};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_table_path() {
+        let output = Output::new("dir/output.parquet");
+        assert_eq!(
+            output.table_path(Table::Files),
+            PathBuf::from("dir/output.parquet")
+        );
+        assert_eq!(
+            output.table_path(Table::Blobs),
+            PathBuf::from("dir/output.blobs.parquet")
+        );
+        assert_eq!(
+            output.shard_path(Table::Files, 1),
+            PathBuf::from("dir/output-00001.parquet")
+        );
+        assert_eq!(
+            output.shard_path(Table::Blobs, 2),
+            PathBuf::from("dir/output.blobs-00002.parquet")
+        );
+        let output = Output::new("output");
+        assert_eq!(
+            output.table_path(Table::Blobs),
+            PathBuf::from("output.blobs")
+        );
+        assert_eq!(
+            output.partition_path("format=tar", 3, "parquet"),
+            PathBuf::from("output/format=tar/part-00003.parquet")
+        );
+        assert_eq!(
+            output.dataset_file_path(None, 1, "abc", "parquet"),
+            PathBuf::from("output/part-00001-abc.parquet")
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use arrow::array::{Array, AsArray};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use ring::digest::{SHA256, digest};
+use std::fmt::Write;
+
+/// The maximum length of an escaped partition value, which keeps the partition directory name
+/// within the 255 byte limit of most filesystems.
+const MAX_ESCAPED_LEN: usize = 200;
+/// The length of the hash that replaces the end of a partition value that is too long
+const HASH_SUFFIX_LEN: usize = 16;
+
+/// The key used to partition the output into a Hive-style directory dataset.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum PartitionBy {
+    /// The input path the file was read from, e.g. `source=image.tar`.
+    Source,
+    /// The detected format of the file, e.g. `format=executable`.
+    Format,
+    /// The first byte of the hash of the file, e.g. `hash_prefix=a1`.
+    HashPrefix,
+}
+
+impl PartitionBy {
+    /// The column that the partition value is taken from. This column is not written to the
+    /// partitioned files, as its value is stored in the directory name.
+    pub fn partition_column(&self) -> Option<&'static str> {
+        match self {
+            PartitionBy::Source => Some("source"),
+            PartitionBy::Format => Some("format"),
+            PartitionBy::HashPrefix => None,
+        }
+    }
+
+    /// Returns the partition directory name for each row in the batch, e.g. `format=executable`.
+    pub fn partition_values(&self, batch: &RecordBatch) -> Result<Vec<String>, ArrowError> {
+        Ok(self
+            .raw_partition_values(batch)?
+            .iter()
+            .map(|value| self.directory_name(value))
+            .collect())
+    }
+
+    /// Returns the unescaped partition value for each row in the batch, e.g. `executable`.
+    pub(crate) fn raw_partition_values(
+        &self,
+        batch: &RecordBatch,
+    ) -> Result<Vec<String>, ArrowError> {
+        let column_name = self.partition_column().unwrap_or("hash");
+        let column = batch.column_by_name(column_name).ok_or_else(|| {
+            ArrowError::SchemaError(format!("Partition column {column_name:?} not found"))
+        })?;
+        assert!(!column.is_nullable(), "Partition column is nullable");
+        let values = match self {
+            PartitionBy::Source | PartitionBy::Format => column
+                .as_string_view()
+                .iter()
+                .map(|value| value.unwrap().to_string())
+                .collect(),
+            PartitionBy::HashPrefix => column
+                .as_fixed_size_binary()
+                .iter()
+                .map(|hash| format!("{:02x}", hash.unwrap()[0]))
+                .collect(),
+        };
+        Ok(values)
+    }
+
+    /// The name of the partition directory holding the rows with the given value.
+    pub(crate) fn directory_name(&self, value: &str) -> String {
+        format!("{self}={}", escape_partition_value(value))
+    }
+}
+
+/// Escape a value for use in a partition directory name, percent-encoding any character that is
+/// not safe to use in a path. Values that are too long for a directory name are cut short, and
+/// end with `~` and a hash of the whole value so that they stay unique. A `~` is otherwise
+/// always escaped, so these names cannot be unescaped by mistake.
+pub(crate) fn escape_partition_value(value: &str) -> String {
+    if value.is_empty() {
+        return "__HIVE_DEFAULT_PARTITION__".to_string();
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_') {
+            escaped.push(byte as char);
+        } else {
+            write!(escaped, "%{byte:02X}").unwrap();
+        }
+    }
+    if escaped.len() > MAX_ESCAPED_LEN {
+        // Do not cut an escape in half
+        let mut end = MAX_ESCAPED_LEN - HASH_SUFFIX_LEN - 1;
+        if let Some(escape) = escaped[end - 2..end].find('%') {
+            end = end - 2 + escape;
+        }
+        escaped.truncate(end);
+        escaped.push('~');
+        for byte in &digest(&SHA256, value.as_bytes()).as_ref()[..HASH_SUFFIX_LEN / 2] {
+            write!(escaped, "{byte:02x}").unwrap();
+        }
+    }
+    escaped
+}
+
+/// Decode any `%XX` escapes in a value. Invalid escapes are left as they are.
+pub(crate) fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::partition::{MAX_ESCAPED_LEN, escape_partition_value, percent_decode};
+
+    #[test]
+    fn test_escape_partition_value() {
+        assert_eq!(escape_partition_value("image.tar"), "image.tar");
+        assert_eq!(
+            escape_partition_value("/data/image 1.tar"),
+            "%2Fdata%2Fimage%201.tar"
+        );
+        assert_eq!(escape_partition_value(""), "__HIVE_DEFAULT_PARTITION__");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        for value in ["image.tar", "/data/image 1.tar", "100%"] {
+            assert_eq!(percent_decode(&escape_partition_value(value)), value);
+        }
+    }
+
+    #[test]
+    fn test_escape_long_partition_value() {
+        let long = format!("/data/{}.tar", "a/".repeat(200));
+        let escaped = escape_partition_value(&long);
+        assert!(escaped.len() <= MAX_ESCAPED_LEN);
+        assert!(escaped.starts_with("%2Fdata%2Fa%2Fa"));
+        // The hash is never split from a partial escape
+        let (prefix, hash) = escaped.rsplit_once('~').unwrap();
+        assert!(!prefix.ends_with('%') && !prefix[prefix.len() - 2..].starts_with('%'));
+        assert_eq!(hash.len(), 16);
+
+        // Values that share the same prefix have different names
+        let other = format!("/data/{}.tar.gz", "a/".repeat(200));
+        assert_ne!(escape_partition_value(&other), escaped);
+        assert_eq!(escape_partition_value(&long), escaped);
+    }
+}
@@ -0,0 +1,352 @@
+use arrow::array::{ArrayRef, AsArray, StringArray, UInt32Array};
+use arrow::compute::{concat_batches, interleave_record_batch, take_record_batch};
+use arrow::datatypes::SchemaRef;
+use arrow::error::ArrowError;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use arrow::row::{OwnedRow, RowConverter, Rows, SortField};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Number of rows in each batch that is spilled to disk or produced by the merge
+const SORTED_BATCH_ROWS: usize = 1024;
+
+/// The order that rows are written in. Sorting clusters similar files together, which improves
+/// compression and lets readers skip row groups and pages using their statistics.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum SortBy {
+    /// The hash of the file
+    Hash,
+    /// The path of the file
+    Path,
+    /// The extension of the file, then the path
+    Extension,
+}
+
+impl SortBy {
+    /// The column the sort key is taken from
+    fn column(&self) -> &'static str {
+        match self {
+            SortBy::Hash => "hash",
+            SortBy::Path | SortBy::Extension => "path",
+        }
+    }
+
+    /// Returns true if a table with this schema can be sorted. The blobs table only contains
+    /// the hash and content, so is only sorted by hash.
+    pub fn can_sort(&self, schema: &SchemaRef) -> bool {
+        schema.index_of(self.column()).is_ok()
+    }
+
+    fn sort_key(&self, batch: &RecordBatch) -> Result<Vec<ArrayRef>, ArrowError> {
+        let column = batch.column_by_name(self.column()).ok_or_else(|| {
+            ArrowError::SchemaError(format!("Sort column {:?} not found", self.column()))
+        })?;
+        Ok(match self {
+            SortBy::Hash | SortBy::Path => vec![column.clone()],
+            SortBy::Extension => {
+                let extensions: StringArray = column
+                    .as_string_view()
+                    .iter()
+                    .map(|path| Some(extension(path.unwrap_or_default())))
+                    .collect();
+                vec![Arc::new(extensions), column.clone()]
+            }
+        })
+    }
+}
+
+/// The lowercased extension of the last component of a path, or an empty string.
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Sorts rows using a bounded amount of memory. Rows are buffered until the memory limit is
+/// reached, then sorted and spilled to a temporary file. When finishing, the spilled runs are
+/// merged to produce the rows in order.
+///
+/// Spill files are written to [std::env::temp_dir], which can be changed with `TMPDIR`.
+pub(crate) struct ExternalSorter {
+    sort_by: SortBy,
+    max_memory: usize,
+    buffered: Vec<RecordBatch>,
+    buffered_memory: usize,
+    runs: Vec<PathBuf>,
+    spill_dir: Option<PathBuf>,
+}
+
+impl ExternalSorter {
+    pub fn new(sort_by: SortBy, max_memory: usize) -> Self {
+        Self {
+            sort_by,
+            max_memory,
+            buffered: vec![],
+            buffered_memory: 0,
+            runs: vec![],
+            spill_dir: None,
+        }
+    }
+
+    pub fn push(&mut self, batch: RecordBatch) -> parquet::errors::Result<()> {
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        self.buffered_memory += batch.get_array_memory_size();
+        self.buffered.push(batch);
+        if self.buffered_memory > self.max_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn converter(&self, key: &[ArrayRef]) -> Result<RowConverter, ArrowError> {
+        RowConverter::new(
+            key.iter()
+                .map(|column| SortField::new(column.data_type().clone()))
+                .collect(),
+        )
+    }
+
+    /// Sort the buffered rows
+    fn sort_buffered(&mut self) -> parquet::errors::Result<Option<RecordBatch>> {
+        let Some(first) = self.buffered.first() else {
+            return Ok(None);
+        };
+        let batch = concat_batches(&first.schema(), &self.buffered)?;
+        self.buffered.clear();
+        self.buffered_memory = 0;
+        let key = self.sort_key(&batch)?;
+        let rows = self.converter(&key)?.convert_columns(&key)?;
+        let mut indices: Vec<u32> = (0..batch.num_rows() as u32).collect();
+        indices.sort_by(|a, b| rows.row(*a as usize).cmp(&rows.row(*b as usize)));
+        Ok(Some(take_record_batch(
+            &batch,
+            &UInt32Array::from(indices),
+        )?))
+    }
+
+    fn sort_key(&self, batch: &RecordBatch) -> Result<Vec<ArrayRef>, ArrowError> {
+        self.sort_by.sort_key(batch)
+    }
+
+    fn spill(&mut self) -> parquet::errors::Result<()> {
+        let Some(sorted) = self.sort_buffered()? else {
+            return Ok(());
+        };
+        let spill_dir = match &self.spill_dir {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = std::env::temp_dir()
+                    .join(format!("archive-to-parquet-sort-{}", uuid::Uuid::new_v4()));
+                std::fs::create_dir_all(&dir)?;
+                self.spill_dir.insert(dir).clone()
+            }
+        };
+        let path = spill_dir.join(format!("run-{:05}.arrows", self.runs.len()));
+        debug!("Spilling {} sorted rows to {path:?}", sorted.num_rows());
+        let mut writer =
+            StreamWriter::try_new(BufWriter::new(File::create(&path)?), &sorted.schema())?;
+        let mut offset = 0;
+        while offset < sorted.num_rows() {
+            let length = SORTED_BATCH_ROWS.min(sorted.num_rows() - offset);
+            writer.write(&sorted.slice(offset, length))?;
+            offset += length;
+        }
+        writer.finish()?;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Produce every row pushed so far in sorted order, then reset the sorter.
+    pub fn finish(
+        &mut self,
+        mut f: impl FnMut(RecordBatch) -> parquet::errors::Result<()>,
+    ) -> parquet::errors::Result<()> {
+        if self.runs.is_empty() {
+            // Everything fits in memory
+            if let Some(sorted) = self.sort_buffered()? {
+                let mut offset = 0;
+                while offset < sorted.num_rows() {
+                    let length = SORTED_BATCH_ROWS.min(sorted.num_rows() - offset);
+                    f(sorted.slice(offset, length))?;
+                    offset += length;
+                }
+            }
+            return Ok(());
+        }
+        self.spill()?;
+        let runs = std::mem::take(&mut self.runs);
+        debug!("Merging {} sorted runs", runs.len());
+        let result = self.merge(&runs, &mut f);
+        for run in runs {
+            std::fs::remove_file(run)?;
+        }
+        result
+    }
+
+    fn merge(
+        &self,
+        runs: &[PathBuf],
+        f: &mut impl FnMut(RecordBatch) -> parquet::errors::Result<()>,
+    ) -> parquet::errors::Result<()> {
+        let mut cursors = vec![];
+        for path in runs {
+            let reader = StreamReader::try_new(BufReader::new(File::open(path)?), None)?;
+            cursors.push(RunCursor {
+                reader,
+                batch: None,
+                offset: 0,
+                slot: 0,
+            });
+        }
+        let converter = {
+            let schema = cursors[0].reader.schema();
+            let empty = RecordBatch::new_empty(schema);
+            self.converter(&self.sort_key(&empty)?)?
+        };
+
+        // The batches that the pending output rows are taken from
+        let mut batches = vec![];
+        let mut heap = BinaryHeap::new();
+        for (run, cursor) in cursors.iter_mut().enumerate() {
+            if cursor.next_batch(self, &converter, &mut batches)? {
+                heap.push(Reverse((cursor.current_row(), run)));
+            }
+        }
+        let mut indices = vec![];
+        while let Some(Reverse((_, run))) = heap.pop() {
+            let cursor = &mut cursors[run];
+            indices.push((cursor.slot, cursor.offset));
+            cursor.offset += 1;
+            let has_row = match &cursor.batch {
+                Some((batch, _)) if cursor.offset < batch.num_rows() => true,
+                _ => cursor.next_batch(self, &converter, &mut batches)?,
+            };
+            if has_row {
+                heap.push(Reverse((cursor.current_row(), run)));
+            }
+            if indices.len() == SORTED_BATCH_ROWS || heap.is_empty() {
+                let batch_refs: Vec<_> = batches.iter().collect();
+                f(interleave_record_batch(&batch_refs, &indices)?)?;
+                indices.clear();
+                // Only keep the batches that are still being read from
+                batches.clear();
+                for cursor in cursors.iter_mut() {
+                    if let Some((batch, _)) = &cursor.batch {
+                        cursor.slot = batches.len();
+                        batches.push(batch.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.spill_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// The position of the merge in a single sorted run
+struct RunCursor {
+    reader: StreamReader<BufReader<File>>,
+    batch: Option<(RecordBatch, Rows)>,
+    offset: usize,
+    /// The index of the current batch in the merge's list of batches
+    slot: usize,
+}
+
+impl RunCursor {
+    /// Read the next batch, returning false once the run is exhausted.
+    fn next_batch(
+        &mut self,
+        sorter: &ExternalSorter,
+        converter: &RowConverter,
+        batches: &mut Vec<RecordBatch>,
+    ) -> parquet::errors::Result<bool> {
+        self.batch = None;
+        self.offset = 0;
+        for batch in self.reader.by_ref() {
+            let batch = batch?;
+            if batch.num_rows() == 0 {
+                continue;
+            }
+            let rows = converter.convert_columns(&sorter.sort_key(&batch)?)?;
+            self.slot = batches.len();
+            batches.push(batch.clone());
+            self.batch = Some((batch, rows));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn current_row(&self) -> OwnedRow {
+        let (_, rows) = self.batch.as_ref().expect("cursor has no batch");
+        rows.row(self.offset).owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::sort::{ExternalSorter, SortBy, extension};
+    use arrow::array::{AsArray, RecordBatch, StringViewArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(extension("a/b.TXT"), "txt");
+        assert_eq!(extension("a.tar/b"), "");
+        assert_eq!(extension("archive.tar.gz"), "gz");
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "path",
+            DataType::Utf8View,
+            false,
+        )]));
+        let paths: Vec<String> = (0..5000)
+            .map(|i| format!("{}.txt", (i * 7919) % 5000))
+            .collect();
+        for max_memory in [usize::MAX, 1] {
+            let mut sorter = ExternalSorter::new(SortBy::Path, max_memory);
+            for chunk in paths.chunks(700) {
+                let array = StringViewArray::from_iter_values(chunk);
+                sorter
+                    .push(RecordBatch::try_new(schema.clone(), vec![Arc::new(array)]).unwrap())
+                    .unwrap();
+            }
+            if max_memory == 1 {
+                assert_eq!(sorter.runs.len(), 8);
+            }
+            let mut sorted = vec![];
+            sorter
+                .finish(|batch| {
+                    let paths = batch.column(0).as_string_view();
+                    sorted.extend(paths.iter().map(|p| p.unwrap().to_string()));
+                    Ok(())
+                })
+                .unwrap();
+            let mut expected = paths.clone();
+            expected.sort();
+            assert_eq!(sorted, expected);
+            assert!(sorter.runs.is_empty());
+        }
+    }
+}
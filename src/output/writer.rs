@@ -0,0 +1,565 @@
+use crate::ConvertionOptions;
+use crate::output::delta::{cast_to_delta, delta_schema};
+use crate::output::format::FormatWriter;
+use crate::output::sort::ExternalSorter;
+use crate::output::{Journal, Output, OutputFileStats, OutputWriter, PartitionBy, Table};
+use crate::provenance::ProvenanceRecorder;
+use arrow::array::UInt32Array;
+use arrow::compute::take_record_batch;
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Everything needed to create a new file for a table.
+struct TableContext {
+    output: Output,
+    table: Table,
+    partition_by: Option<PartitionBy>,
+    schema: SchemaRef,
+    options: ConvertionOptions,
+    /// Unique to each run, used to name files that are appended to a dataset
+    run_id: String,
+    provenance: Option<Arc<ProvenanceRecorder>>,
+}
+
+impl TableContext {
+    fn is_rolling(&self) -> bool {
+        self.options.max_file_size.is_some() || self.options.max_file_rows.is_some()
+    }
+
+    /// The path of a file of the partition with the given (unescaped) value
+    fn file_path(&self, partition: Option<&str>, shard: usize) -> PathBuf {
+        let partition = partition
+            .zip(self.partition_by)
+            .map(|(value, partition_by)| partition_by.directory_name(value));
+        let partition = partition.as_deref();
+        if self.options.is_appending() {
+            return self.output.dataset_file_path(
+                partition,
+                shard,
+                &self.run_id,
+                self.options.output_format.extension(),
+            );
+        }
+        match partition {
+            Some(partition) => {
+                self.output
+                    .partition_path(partition, shard, self.options.output_format.extension())
+            }
+            None if self.is_rolling() => self.output.shard_path(self.table, shard),
+            None => self.output.table_path(self.table),
+        }
+    }
+}
+
+/// Writes rows to a sequence of files, rolling over to a new file once the maximum file size
+/// or row count is reached.
+#[derive(Default)]
+struct RollingWriter {
+    writer: Option<(PathBuf, FormatWriter<OutputWriter>)>,
+    partition: Option<String>,
+    next_shard: usize,
+    last_used: u64,
+}
+
+impl RollingWriter {
+    fn open_next_file(
+        &mut self,
+        ctx: &TableContext,
+        partition: Option<&str>,
+    ) -> parquet::errors::Result<&mut FormatWriter<OutputWriter>> {
+        let path = ctx.file_path(partition, self.next_shard);
+        self.partition = partition.map(str::to_string);
+        self.next_shard += 1;
+        debug!("Opening {} file {path:?}", ctx.table);
+        // Files appended to a dataset are moved into place once they are committed, either in
+        // the journal or in a Delta commit, so an interrupted run never leaves a partial file
+        let writer = match ctx.options.is_appending() {
+            true => ctx.output.create(&Journal::in_progress_path(&path))?,
+            false => ctx.output.create(&path)?,
+        };
+        let writer = FormatWriter::try_new(writer, ctx.schema.clone(), &ctx.options)?;
+        Ok(&mut self.writer.insert((path, writer)).1)
+    }
+
+    fn finish_file(
+        &mut self,
+        ctx: &TableContext,
+    ) -> parquet::errors::Result<Option<OutputFileStats>> {
+        let Some((path, mut writer)) = self.writer.take() else {
+            return Ok(None);
+        };
+        let metadata = ctx
+            .provenance
+            .as_ref()
+            .map(|provenance| provenance.key_value_metadata())
+            .unwrap_or_default();
+        let (rows, bytes) = writer.finish(&metadata)?;
+        let stats = OutputFileStats {
+            table: ctx.table,
+            path,
+            rows,
+            bytes,
+            partition: self.partition.clone(),
+        };
+        debug!("Finished writing file: {stats}");
+        Ok(Some(stats))
+    }
+
+    fn write(
+        &mut self,
+        ctx: &TableContext,
+        partition: Option<&str>,
+        mut batch: RecordBatch,
+        files: &mut Vec<OutputFileStats>,
+    ) -> parquet::errors::Result<()> {
+        while batch.num_rows() > 0 {
+            let writer = match self.writer {
+                Some((_, ref mut writer)) => writer,
+                None => self.open_next_file(ctx, partition)?,
+            };
+            // Only write as many rows as the current file has space for
+            let num_rows = match ctx.options.max_file_rows {
+                Some(max_rows) => {
+                    (max_rows.saturating_sub(writer.rows()) as usize).clamp(1, batch.num_rows())
+                }
+                None => batch.num_rows(),
+            };
+            writer.write(&batch.slice(0, num_rows))?;
+            batch = batch.slice(num_rows, batch.num_rows() - num_rows);
+
+            if self.is_file_full(ctx) {
+                files.extend(self.finish_file(ctx)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_file_full(&self, ctx: &TableContext) -> bool {
+        let Some((_, writer)) = &self.writer else {
+            return false;
+        };
+        ctx.options
+            .max_file_rows
+            .is_some_and(|max| writer.rows() >= max)
+            || ctx
+                .options
+                .max_file_size
+                .is_some_and(|max| writer.size() >= max.as_u64())
+    }
+
+    fn memory_size(&self) -> usize {
+        self.writer
+            .as_ref()
+            .map(|(_, writer)| writer.memory_size())
+            .unwrap_or_default()
+    }
+
+    fn flush(&mut self) -> parquet::errors::Result<()> {
+        match &mut self.writer {
+            Some((_, writer)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes a single [Table] to an [Output]. If a maximum file size or row count is set then the
+/// table is split into several files, rolling over to a new file once the limit is reached.
+///
+/// If the table is partitioned then rows are routed to a separate set of files for each
+/// partition. The number of partitions with an open file and the memory used by buffered
+/// rows are bounded, closing the least recently used file or flushing the largest buffer.
+pub struct TableWriter {
+    ctx: TableContext,
+    partitions: HashMap<Option<String>, RollingWriter>,
+    files: Vec<OutputFileStats>,
+    checkpointed_files: usize,
+    writes: u64,
+    sorter: Option<ExternalSorter>,
+}
+
+impl TableWriter {
+    pub fn try_new(
+        output: &Output,
+        table: Table,
+        schema: SchemaRef,
+        options: &ConvertionOptions,
+    ) -> parquet::errors::Result<Self> {
+        // Only the main table is partitioned
+        let partition_by = options.partition_by.filter(|_| table == Table::Files);
+        let sorter = options
+            .sort_by
+            .filter(|sort_by| sort_by.can_sort(&schema))
+            .map(|sort_by| ExternalSorter::new(sort_by, options.max_sort_memory.as_u64() as usize));
+        let schema = match partition_by.and_then(|p| p.partition_column()) {
+            Some(column) => {
+                let index = schema.index_of(column)?;
+                let columns: Vec<_> = (0..schema.fields().len())
+                    .filter(|idx| *idx != index)
+                    .collect();
+                Arc::new(schema.project(&columns)?)
+            }
+            None => schema,
+        };
+        let schema = match options.output_format.is_delta() {
+            true => delta_schema(&schema),
+            false => schema,
+        };
+        let mut writer = Self {
+            ctx: TableContext {
+                output: output.clone(),
+                table,
+                partition_by,
+                schema,
+                options: options.clone(),
+                run_id: Uuid::new_v4().to_string(),
+                provenance: None,
+            },
+            partitions: HashMap::new(),
+            files: vec![],
+            checkpointed_files: 0,
+            writes: 0,
+            sorter,
+        };
+        match partition_by {
+            Some(_) => std::fs::create_dir_all(output.path())?,
+            // Appending no rows to a dataset does not need any new files
+            None if options.is_appending() => {}
+            None => {
+                // Always create the first file, so that a table with no rows is still written
+                writer
+                    .partitions
+                    .entry(None)
+                    .or_default()
+                    .open_next_file(&writer.ctx, None)?;
+            }
+        }
+        Ok(writer)
+    }
+
+    /// Record the provenance of the conversion in every file that is finished
+    pub(crate) fn set_provenance(&mut self, provenance: Arc<ProvenanceRecorder>) {
+        self.ctx.provenance = Some(provenance);
+    }
+
+    pub fn table(&self) -> Table {
+        self.ctx.table
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> parquet::errors::Result<()> {
+        match &mut self.sorter {
+            Some(sorter) => sorter.push(batch.clone()),
+            None => self.write_rows(batch),
+        }
+    }
+
+    /// Write all the rows held by the sorter, in order
+    fn write_sorted(&mut self) -> parquet::errors::Result<()> {
+        let Some(mut sorter) = self.sorter.take() else {
+            return Ok(());
+        };
+        let result = sorter.finish(|batch| self.write_rows(&batch));
+        self.sorter = Some(sorter);
+        result
+    }
+
+    fn write_rows(&mut self, batch: &RecordBatch) -> parquet::errors::Result<()> {
+        let Some(partition_by) = self.ctx.partition_by else {
+            return self.write_partition(None, batch.clone());
+        };
+
+        let mut partitions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for (idx, value) in partition_by
+            .raw_partition_values(batch)?
+            .into_iter()
+            .enumerate()
+        {
+            partitions.entry(value).or_default().push(idx as u32);
+        }
+        let columns: Vec<_> = self
+            .ctx
+            .schema
+            .fields()
+            .iter()
+            .map(|field| batch.schema().index_of(field.name()))
+            .collect::<Result<_, _>>()?;
+        for (partition, indices) in partitions {
+            let partition_batch = take_record_batch(batch, &UInt32Array::from(indices))?;
+            self.write_partition(Some(partition), partition_batch.project(&columns)?)?;
+        }
+        self.enforce_partition_limits()
+    }
+
+    fn write_partition(
+        &mut self,
+        partition: Option<String>,
+        batch: RecordBatch,
+    ) -> parquet::errors::Result<()> {
+        let batch = match self.ctx.options.output_format.is_delta() {
+            true => cast_to_delta(&batch, &self.ctx.schema)?,
+            false => batch,
+        };
+        self.writes += 1;
+        let writer = self.partitions.entry(partition.clone()).or_default();
+        writer.last_used = self.writes;
+        writer.write(&self.ctx, partition.as_deref(), batch, &mut self.files)
+    }
+
+    fn enforce_partition_limits(&mut self) -> parquet::errors::Result<()> {
+        let max_open = self.ctx.options.max_open_partitions.max(1);
+        let mut open: Vec<_> = self
+            .partitions
+            .values_mut()
+            .filter(|w| w.writer.is_some())
+            .collect();
+        if open.len() > max_open {
+            // Close the least recently used files
+            open.sort_by_key(|w| w.last_used);
+            let to_close = open.len() - max_open;
+            for writer in open.into_iter().take(to_close) {
+                self.files.extend(writer.finish_file(&self.ctx)?);
+            }
+        }
+
+        let max_memory = self.ctx.options.max_partition_memory.as_u64() as usize;
+        while self
+            .partitions
+            .values()
+            .map(|w| w.memory_size())
+            .sum::<usize>()
+            > max_memory
+        {
+            let largest = self
+                .partitions
+                .values_mut()
+                .max_by_key(|w| w.memory_size())
+                .unwrap();
+            if largest.memory_size() == 0 {
+                break;
+            }
+            largest.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> parquet::errors::Result<()> {
+        for writer in self.partitions.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finish every open file, returning the files that have been finished since the last
+    /// checkpoint. Any further rows are written to new files.
+    pub fn checkpoint(&mut self) -> parquet::errors::Result<Vec<OutputFileStats>> {
+        self.write_sorted()?;
+        for writer in self.partitions.values_mut() {
+            self.files.extend(writer.finish_file(&self.ctx)?);
+        }
+        let files = self.files[self.checkpointed_files..].to_vec();
+        self.checkpointed_files = self.files.len();
+        Ok(files)
+    }
+
+    /// Finish writing the table, returning statistics about each file written.
+    pub fn finish(mut self) -> parquet::errors::Result<Vec<OutputFileStats>> {
+        self.write_sorted()?;
+        for writer in self.partitions.values_mut() {
+            self.files.extend(writer.finish_file(&self.ctx)?);
+        }
+        Ok(self.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::{Output, Table, TableWriter};
+    use crate::{ConvertionOptions, PartitionBy};
+    use arrow::array::{ArrayRef, LargeBinaryArray, RecordBatch, StringViewArray};
+    use byte_unit::Byte;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::basic::Compression;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// A batch of rows from the given sources, with 1KB of distinct content each so that it
+    /// isn't dictionary encoded. `first` is the number of rows in the previous batches.
+    fn batch(sources: &[&str], first: u32) -> RecordBatch {
+        RecordBatch::try_from_iter([
+            (
+                "source",
+                Arc::new(StringViewArray::from_iter_values(sources)) as ArrayRef,
+            ),
+            (
+                "content",
+                Arc::new(LargeBinaryArray::from_iter_values(
+                    (first..)
+                        .take(sources.len())
+                        .map(|i| i.to_le_bytes().repeat(256)),
+                )),
+            ),
+        ])
+        .unwrap()
+    }
+
+    fn file_rows(path: &Path) -> i64 {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap();
+        reader.metadata().file_metadata().num_rows()
+    }
+
+    #[test]
+    fn test_rollover() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir.join("output.parquet"));
+        let sources = ["input.tar"; 100];
+        let schema = batch(&sources, 0).schema();
+
+        let mut options = ConvertionOptions::const_default();
+        options.compression = Compression::UNCOMPRESSED;
+        options.max_file_rows = Some(250);
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, schema.clone(), &options).unwrap();
+        for i in 0..6 {
+            writer.write(&batch(&sources, i * 100)).unwrap();
+        }
+        let files = writer.finish().unwrap();
+        let rows: Vec<_> = files.iter().map(|file| file_rows(&file.path)).collect();
+        assert_eq!(rows, vec![250, 250, 100]);
+        assert_eq!(files[2].path, dir.join("output-00002.parquet"));
+
+        // Files are rolled over once they reach the maximum size, so each file has three
+        // batches of 100KB
+        options.max_file_rows = None;
+        options.max_file_size = Some(Byte::from(250 * 1024u64));
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, schema.clone(), &options).unwrap();
+        for i in 0..10 {
+            writer.write(&batch(&sources, i * 100)).unwrap();
+        }
+        let files = writer.finish().unwrap();
+        let rows: Vec<_> = files.iter().map(|file| file_rows(&file.path)).collect();
+        assert_eq!(rows, vec![300, 300, 300, 100]);
+        assert!(files[..3].iter().all(|file| file.bytes >= 250 * 1024));
+
+        // Whichever limit is reached first rolls over to a new file
+        options.max_file_rows = Some(250);
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, schema.clone(), &options).unwrap();
+        for i in 0..6 {
+            writer.write(&batch(&sources, i * 100)).unwrap();
+        }
+        let rows: Vec<_> = writer
+            .finish()
+            .unwrap()
+            .iter()
+            .map(|file| file_rows(&file.path))
+            .collect();
+        assert_eq!(rows, vec![250, 250, 100]);
+    }
+
+    #[test]
+    fn test_partition_routing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let sources = ["a.tar", "b/c.tar", "a.tar", "a.tar"];
+        let schema = batch(&sources, 0).schema();
+
+        let mut options = ConvertionOptions::const_default();
+        options.partition_by = Some(PartitionBy::Source);
+        let mut writer = TableWriter::try_new(&output, Table::Files, schema, &options).unwrap();
+        writer.write(&batch(&sources, 0)).unwrap();
+        let mut files = writer.finish().unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let paths: Vec<_> = files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("source=a.tar/part-00000.parquet"),
+                dir.join("source=b%2Fc.tar/part-00000.parquet"),
+            ]
+        );
+        let rows: Vec<_> = files.iter().map(|file| file_rows(&file.path)).collect();
+        assert_eq!(rows, vec![3, 1]);
+
+        // The partition column is stored in the directory name rather than the file
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&paths[0]).unwrap()).unwrap();
+        let columns: Vec<_> = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(columns, vec!["content"]);
+    }
+
+    #[test]
+    fn test_partition_limits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir);
+        let schema = batch(&["a"], 0).schema();
+
+        // Writing to a third partition closes the least recently used file
+        let mut options = ConvertionOptions::const_default();
+        options.partition_by = Some(PartitionBy::Source);
+        options.max_open_partitions = 2;
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, schema.clone(), &options).unwrap();
+        for (i, source) in ["a", "b", "a", "c"].into_iter().enumerate() {
+            writer.write(&batch(&[source], i as u32)).unwrap();
+        }
+        let open = |writer: &TableWriter, partition: &str| {
+            writer.partitions[&Some(partition.to_string())]
+                .writer
+                .is_some()
+        };
+        assert!(open(&writer, "a"));
+        assert!(!open(&writer, "b"));
+        assert!(open(&writer, "c"));
+        assert_eq!(writer.files.len(), 1);
+        assert_eq!(
+            writer.files[0].path,
+            dir.join("source=b/part-00000.parquet")
+        );
+        assert_eq!(writer.files[0].partition.as_deref(), Some("b"));
+
+        // A closed partition that is written to again starts a new file
+        writer.write(&batch(&["b"], 4)).unwrap();
+        assert!(!open(&writer, "a"));
+        let files = writer.finish().unwrap();
+        assert_eq!(files.len(), 4);
+        assert!(
+            files
+                .iter()
+                .any(|file| file.path == dir.join("source=b/part-00001.parquet"))
+        );
+
+        // Buffered rows are flushed once they use more than the maximum memory
+        options.max_open_partitions = 64;
+        options.max_partition_memory = Byte::from(64 * 1024u64);
+        let max_memory = 64 * 1024;
+        let mut writer =
+            TableWriter::try_new(&output, Table::Files, schema.clone(), &options).unwrap();
+        for i in 0..40 {
+            let source = ["a", "b", "c", "d"][i % 4];
+            writer.write(&batch(&[source; 10], i as u32 * 10)).unwrap();
+            let memory: usize = writer.partitions.values().map(|w| w.memory_size()).sum();
+            assert!(memory <= max_memory, "{memory} bytes buffered");
+        }
+        for file in writer.finish().unwrap() {
+            let reader =
+                ParquetRecordBatchReaderBuilder::try_new(File::open(&file.path).unwrap()).unwrap();
+            assert!(reader.metadata().num_row_groups() > 1);
+            assert_eq!(file.rows, 100);
+        }
+    }
+}
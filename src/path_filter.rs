@@ -0,0 +1,105 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Include and exclude glob patterns matched against the full nested path of an entry, e.g.
+/// `nested.tar/src/main.py`. `*` does not match `/`, while `**` matches any number of
+/// directories: `**/*.py` matches every Python file and `**/node_modules/**` matches everything
+/// inside a `node_modules` directory.
+///
+/// A path matches if it matches any include pattern, or there are none, and does not match any
+/// exclude pattern.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// A filter that matches every path
+    pub const fn const_default() -> Self {
+        Self {
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            include: None,
+            exclude: None,
+        }
+    }
+
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Result<Self, globset::Error> {
+        Ok(Self {
+            include: Self::glob_set(&include)?,
+            exclude: Self::glob_set(&exclude)?,
+            include_patterns: include,
+            exclude_patterns: exclude,
+        })
+    }
+
+    fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+        GlobBuilder::new(pattern).literal_separator(true).build()
+    }
+
+    fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Self::glob(pattern)?);
+        }
+        Ok(Some(builder.build()?))
+    }
+
+    /// Returns true if the filter matches every path
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|set| set.is_match(path))
+    }
+}
+
+impl PartialEq for PathFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.include_patterns == other.include_patterns
+            && self.exclude_patterns == other.exclude_patterns
+    }
+}
+
+impl Display for PathFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "include={:?} exclude={:?}",
+            self.include_patterns, self.exclude_patterns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::path_filter::PathFilter;
+    use std::path::Path;
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(
+            vec!["**/*.py".into(), "*.md".into()],
+            vec!["**/node_modules/**".into()],
+        )
+        .unwrap();
+        assert!(filter.matches(Path::new("main.py")));
+        assert!(filter.matches(Path::new("nested.tar/src/main.py")));
+        assert!(filter.matches(Path::new("README.md")));
+        assert!(!filter.matches(Path::new("docs/README.md")));
+        assert!(!filter.matches(Path::new("src/main.rs")));
+        assert!(!filter.matches(Path::new("app/node_modules/pkg/setup.py")));
+
+        assert!(PathFilter::const_default().matches(Path::new("anything")));
+        assert!(PathFilter::new(vec!["[".into()], vec![]).is_err());
+    }
+}
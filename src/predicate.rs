@@ -0,0 +1,518 @@
+use crate::FormatKind;
+use crate::classify::detect_encoding;
+use byte_unit::Byte;
+use regex::Regex;
+use std::cell::OnceCell;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A filter expression over the metadata of an entry, e.g.
+/// `size < 10MB and format != 'executable' and path ~ '\.rs$' and depth <= 3`.
+///
+/// Fields:
+/// - `path`, `extension`, `format` and `mime_type` are strings. They can be compared with `=`
+///   and `!=`, or matched against a regex with `~` and `!~`. Only `path` is case-sensitive.
+/// - `size` and `depth` are numbers, compared with `=`, `!=`, `<`, `<=`, `>` and `>=`. Sizes can
+///   have a unit, e.g. `10MB` or `1.5GiB`. `depth` is the number of archives an entry is in.
+/// - `is_text` and `is_binary` are true if the content is, or is not, text in a detected encoding.
+///
+/// Conditions are combined with `and`, `or`, `not` and parentheses. Strings are quoted with `'`
+/// or `"`, and a quote inside a string is escaped with `\`.
+///
+/// Predicates are evaluated before an entry is read where possible. Conditions on the content,
+/// and on the size when it is not known up front, are evaluated once the entry has been read.
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Size(CmpOp, u64),
+    Depth(CmpOp, u64),
+    Text(TextField, TextOp),
+    IsText(bool),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn compare(&self, left: u64, right: u64) -> bool {
+        match self {
+            CmpOp::Eq => left == right,
+            CmpOp::Ne => left != right,
+            CmpOp::Lt => left < right,
+            CmpOp::Le => left <= right,
+            CmpOp::Gt => left > right,
+            CmpOp::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum TextField {
+    Path,
+    Extension,
+    Format,
+    MimeType,
+}
+
+#[derive(Debug, Clone)]
+enum TextOp {
+    Eq(String),
+    Ne(String),
+    Matches(Regex),
+    NotMatches(Regex),
+}
+
+/// The metadata of an entry that is known before it is read
+pub(crate) struct EntryMetadata<'a> {
+    /// The full nested path of the entry
+    pub path: &'a Path,
+    /// The size of the content, if it is known before reading it
+    pub size: Option<u64>,
+    pub format: FormatKind,
+    pub mime_type: Option<&'a str>,
+    pub depth: usize,
+}
+
+/// The content of an entry that has been read
+pub(crate) struct ContentMetadata<'a> {
+    size: u64,
+    content: Option<&'a [u8]>,
+    truncated: bool,
+    is_text: OnceCell<bool>,
+}
+
+impl<'a> ContentMetadata<'a> {
+    /// The size of the full content, and the content that was stored if there is any
+    pub fn new(size: u64, content: Option<&'a [u8]>, truncated: bool) -> Self {
+        Self {
+            size,
+            content,
+            truncated,
+            is_text: OnceCell::new(),
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        *self.is_text.get_or_init(|| {
+            self.content
+                .is_some_and(|content| detect_encoding(content, self.truncated).is_some())
+        })
+    }
+}
+
+impl Predicate {
+    /// Returns true if the predicate depends on the content of entries, so cannot be used
+    /// without the content.
+    pub fn uses_content(&self) -> bool {
+        fn uses_content(expr: &Expr) -> bool {
+            match expr {
+                Expr::And(left, right) | Expr::Or(left, right) => {
+                    uses_content(left) || uses_content(right)
+                }
+                Expr::Not(expr) => uses_content(expr),
+                Expr::IsText(_) => true,
+                _ => false,
+            }
+        }
+        uses_content(&self.expr)
+    }
+
+    /// Evaluate the predicate for an entry. Without the content, returns `None` if the result
+    /// depends on the content or on a size that is not known yet.
+    pub(crate) fn evaluate(
+        &self,
+        entry: &EntryMetadata,
+        content: Option<&ContentMetadata>,
+    ) -> Option<bool> {
+        Self::evaluate_expr(&self.expr, entry, content)
+    }
+
+    fn evaluate_expr(
+        expr: &Expr,
+        entry: &EntryMetadata,
+        content: Option<&ContentMetadata>,
+    ) -> Option<bool> {
+        let evaluate = |expr| Self::evaluate_expr(expr, entry, content);
+        match expr {
+            Expr::And(left, right) => match (evaluate(left), evaluate(right)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(left, right) => match (evaluate(left), evaluate(right)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Not(expr) => evaluate(expr).map(|result| !result),
+            Expr::Size(op, size) => {
+                let entry_size = content.map(|content| content.size).or(entry.size)?;
+                Some(op.compare(entry_size, *size))
+            }
+            Expr::Depth(op, depth) => Some(op.compare(entry.depth as u64, *depth)),
+            Expr::Text(field, op) => {
+                let extension;
+                let value = match field {
+                    TextField::Path => &*entry.path.to_string_lossy(),
+                    TextField::Extension => {
+                        extension = entry
+                            .path
+                            .extension()
+                            .map(|ext| ext.to_string_lossy())
+                            .unwrap_or_default();
+                        &*extension
+                    }
+                    TextField::Format => &*entry.format.to_string(),
+                    TextField::MimeType => entry.mime_type.unwrap_or_default(),
+                };
+                let equals = |expected: &str| match field {
+                    TextField::Path => value == expected,
+                    _ => value.eq_ignore_ascii_case(expected),
+                };
+                Some(match op {
+                    TextOp::Eq(expected) => equals(expected),
+                    TextOp::Ne(expected) => !equals(expected),
+                    TextOp::Matches(regex) => regex.is_match(value),
+                    TextOp::NotMatches(regex) => !regex.is_match(value),
+                })
+            }
+            Expr::IsText(is_text) => content.map(|content| content.is_text() == *is_text),
+        }
+    }
+}
+
+impl PartialEq for Predicate {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {token} in filter {s:?}"));
+        }
+        Ok(Self {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A field name, keyword or unquoted value
+    Word(String),
+    /// A quoted string
+    String(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{word:?}"),
+            Token::String(string) => write!(f, "'{string}'"),
+            Token::Op(op) => write!(f, "{op:?}"),
+            Token::LParen => write!(f, "\"(\""),
+            Token::RParen => write!(f, "\")\""),
+        }
+    }
+}
+
+const OPERATORS: [&str; 9] = ["==", "!=", "<=", ">=", "!~", "=", "<", ">", "~"];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(idx, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RParen);
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) if chars.peek().is_some_and(|(_, next)| *next == c) => {
+                        string.push(c);
+                        chars.next();
+                    }
+                    Some((_, next)) if next == c => break,
+                    Some((_, next)) => string.push(next),
+                    None => return Err(format!("unterminated string in filter {s:?}")),
+                }
+            }
+            tokens.push(Token::String(string));
+        } else if let Some(op) = OPERATORS.iter().find(|op| s[idx..].starts_with(*op)) {
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(Token::Op(op));
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.' || c == '/' || c == '-') {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            return Err(format!("unexpected character {c:?} in filter {s:?}"));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_not()?;
+        while self.next_is_keyword("and") {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.next_is_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<Expr, String> {
+        let field = match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                return match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    Some(token) => Err(format!("expected \")\", found {token}")),
+                    None => Err("expected \")\"".to_string()),
+                };
+            }
+            Some(Token::Word(field)) => field.to_ascii_lowercase(),
+            Some(token) => return Err(format!("expected a field, found {token}")),
+            None => return Err("expected a field".to_string()),
+        };
+        match field.as_str() {
+            "is_text" => return Ok(Expr::IsText(true)),
+            "is_binary" => return Ok(Expr::IsText(false)),
+            _ => {}
+        }
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(token) => {
+                return Err(format!("expected an operator after {field}, found {token}"));
+            }
+            None => return Err(format!("expected an operator after {field}")),
+        };
+        let value = match self.next() {
+            Some(Token::Word(value) | Token::String(value)) => value,
+            Some(token) => {
+                return Err(format!(
+                    "expected a value after {field} {op}, found {token}"
+                ));
+            }
+            None => return Err(format!("expected a value after {field} {op}")),
+        };
+        match field.as_str() {
+            "size" => {
+                let size = Byte::parse_str(&value, true)
+                    .map_err(|e| format!("invalid size {value:?}: {e}"))?;
+                Ok(Expr::Size(Self::cmp_op(&field, op)?, size.as_u64()))
+            }
+            "depth" => {
+                let depth = value
+                    .parse()
+                    .map_err(|e| format!("invalid depth {value:?}: {e}"))?;
+                Ok(Expr::Depth(Self::cmp_op(&field, op)?, depth))
+            }
+            "path" | "extension" | "format" | "mime_type" => {
+                let field = match field.as_str() {
+                    "path" => TextField::Path,
+                    "extension" => TextField::Extension,
+                    "format" => TextField::Format,
+                    _ => TextField::MimeType,
+                };
+                if field == TextField::Format
+                    && matches!(op, "=" | "==" | "!=")
+                    && FormatKind::from_str(&value).is_err()
+                {
+                    return Err(format!("unknown format {value:?}"));
+                }
+                let regex = || Regex::new(&value).map_err(|e| format!("invalid regex: {e}"));
+                let op = match op {
+                    "=" | "==" => TextOp::Eq(value),
+                    "!=" => TextOp::Ne(value),
+                    "~" => TextOp::Matches(regex()?),
+                    "!~" => TextOp::NotMatches(regex()?),
+                    op => return Err(format!("{op} cannot be used with {field:?}")),
+                };
+                Ok(Expr::Text(field, op))
+            }
+            _ => Err(format!(
+                "unknown field {field:?}: expected path, extension, format, mime_type, size, depth, is_text or is_binary"
+            )),
+        }
+    }
+
+    fn cmp_op(field: &str, op: &str) -> Result<CmpOp, String> {
+        Ok(match op {
+            "=" | "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            op => return Err(format!("{op} cannot be used with {field}")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FormatKind;
+    use crate::predicate::{ContentMetadata, EntryMetadata, Predicate};
+    use std::path::Path;
+    use std::str::FromStr;
+
+    fn entry(path: &str, size: Option<u64>) -> EntryMetadata<'_> {
+        EntryMetadata {
+            path: Path::new(path),
+            size,
+            format: FormatKind::Unknown,
+            mime_type: None,
+            depth: 1,
+        }
+    }
+
+    #[test]
+    fn test_predicate() {
+        let predicate = Predicate::from_str(
+            r"size < 10MB and format != 'executable' and path ~ '\.rs$' and depth <= 3",
+        )
+        .unwrap();
+        assert!(!predicate.uses_content());
+        assert_eq!(
+            predicate.evaluate(&entry("src/main.rs", Some(100)), None),
+            Some(true)
+        );
+        assert_eq!(
+            predicate.evaluate(&entry("src/main.py", None), None),
+            Some(false)
+        );
+        assert_eq!(
+            predicate.evaluate(&entry("src/main.rs", Some(20_000_000)), None),
+            Some(false)
+        );
+        // The size is not known until the entry is read
+        assert_eq!(predicate.evaluate(&entry("src/main.rs", None), None), None);
+        let content = ContentMetadata::new(100, None, false);
+        assert_eq!(
+            predicate.evaluate(&entry("src/main.rs", None), Some(&content)),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_predicate_content() {
+        let predicate =
+            Predicate::from_str("extension = PY or (not is_text and mime_type = 'image/png')")
+                .unwrap();
+        assert!(predicate.uses_content());
+        assert_eq!(predicate.evaluate(&entry("a.py", None), None), Some(true));
+        assert_eq!(predicate.evaluate(&entry("a.txt", None), None), Some(false));
+        let mut png = entry("a.png", None);
+        png.mime_type = Some("image/png");
+        assert_eq!(predicate.evaluate(&png, None), None);
+        let binary = ContentMetadata::new(2, Some(&[0x00, 0xfe]), false);
+        assert_eq!(predicate.evaluate(&png, Some(&binary)), Some(true));
+        let text = ContentMetadata::new(2, Some(b"hi"), false);
+        assert_eq!(predicate.evaluate(&png, Some(&text)), Some(false));
+    }
+
+    #[test]
+    fn test_predicate_errors() {
+        for invalid in [
+            "size <",
+            "size ~ 'a'",
+            "name = 'a'",
+            "format = 'exe'",
+            "path ~ '('",
+            "(depth = 1",
+            "depth = 1 depth = 2",
+            "path = 'unterminated",
+        ] {
+            assert!(Predicate::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+}
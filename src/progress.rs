@@ -91,6 +91,9 @@ impl OutputCounter {
             total_entries_bytes: self.counters.total_entries_bytes.load(ordering),
             output_rows: self.counters.output_rows.load(ordering),
             output_bytes: self.counters.output_bytes.load(ordering),
+            output_files: vec![],
+            cancelled: false,
+            unfinished_inputs: vec![],
         }
     }
 
@@ -0,0 +1,196 @@
+use parquet::basic::Compression;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Properties used when writing Parquet files. Any property that is not set uses the default
+/// shown in its documentation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParquetOptions {
+    /// Maximum number of rows in a row group. Defaults to 1,048,576
+    pub max_row_group_size: Option<usize>,
+    /// Maximum size of a data page in bytes. Defaults to 1MB
+    pub data_page_size_limit: Option<usize>,
+    /// Maximum number of rows in a data page. Defaults to 2,000
+    pub data_page_row_count_limit: Option<usize>,
+    /// Number of rows written to a column at once. Defaults to 1,024
+    pub write_batch_size: Option<usize>,
+    /// Columns to write bloom filters for. Defaults to `source`, `path` and `hash`
+    pub bloom_filter_columns: Option<Vec<String>>,
+    /// Columns to write page statistics for. Defaults to `source`, `path`, `size`, `hash`,
+    /// `format` and `mime_type`
+    pub statistics_columns: Option<Vec<String>>,
+    /// Columns to dictionary encode. Defaults to `source`, `path`, `format` and `mime_type`
+    pub dictionary_columns: Option<Vec<String>>,
+    /// The compression codec and level for individual columns, e.g. `content = "zstd(19)"`.
+    /// Columns that are not listed use the default compression.
+    #[serde(deserialize_with = "deserialize_column_compression")]
+    pub column_compression: BTreeMap<String, Compression>,
+}
+
+impl ParquetOptions {
+    pub const fn const_default() -> Self {
+        Self {
+            max_row_group_size: None,
+            data_page_size_limit: None,
+            data_page_row_count_limit: None,
+            write_batch_size: None,
+            bloom_filter_columns: None,
+            statistics_columns: None,
+            dictionary_columns: None,
+            column_compression: BTreeMap::new(),
+        }
+    }
+
+    /// Every column named by the options
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        [
+            &self.bloom_filter_columns,
+            &self.statistics_columns,
+            &self.dictionary_columns,
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .chain(self.column_compression.keys())
+        .map(String::as_str)
+    }
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        Self::const_default()
+    }
+}
+
+impl Display for ParquetOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut fields = vec![];
+        let mut field = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                fields.push(format!("{name}={value}"));
+            }
+        };
+        field(
+            "max_row_group_size",
+            self.max_row_group_size.map(|v| v.to_string()),
+        );
+        field(
+            "data_page_size_limit",
+            self.data_page_size_limit.map(|v| v.to_string()),
+        );
+        field(
+            "data_page_row_count_limit",
+            self.data_page_row_count_limit.map(|v| v.to_string()),
+        );
+        field(
+            "write_batch_size",
+            self.write_batch_size.map(|v| v.to_string()),
+        );
+        field(
+            "bloom_filter_columns",
+            self.bloom_filter_columns.as_ref().map(|v| v.join(",")),
+        );
+        field(
+            "statistics_columns",
+            self.statistics_columns.as_ref().map(|v| v.join(",")),
+        );
+        field(
+            "dictionary_columns",
+            self.dictionary_columns.as_ref().map(|v| v.join(",")),
+        );
+        for (column, compression) in &self.column_compression {
+            field(
+                &format!("compression[{column}]"),
+                Some(compression.to_string()),
+            );
+        }
+        write!(f, "[{}]", fields.join(", "))
+    }
+}
+
+/// A config file containing writer options, in TOML format:
+///
+/// ```toml
+/// compression = "zstd(3)"
+///
+/// [parquet]
+/// max_row_group_size = 100000
+///
+/// [parquet.column_compression]
+/// content = "zstd(19)"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The default compression codec and level
+    #[serde(deserialize_with = "deserialize_compression")]
+    pub compression: Option<Compression>,
+    pub parquet: ParquetOptions,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn deserialize_compression<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Compression>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|codec| codec.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+fn deserialize_column_compression<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Compression>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(column, codec)| Ok((column, codec.parse().map_err(D::Error::custom)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::properties::ConfigFile;
+    use parquet::basic::{Compression, ZstdLevel};
+
+    #[test]
+    fn test_config_file() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            compression = "zstd(3)"
+            [parquet]
+            max_row_group_size = 1000
+            bloom_filter_columns = ["hash"]
+            [parquet.column_compression]
+            content = "zstd(19)"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.compression,
+            Some(Compression::ZSTD(ZstdLevel::try_new(3).unwrap()))
+        );
+        assert_eq!(config.parquet.max_row_group_size, Some(1000));
+        assert_eq!(config.parquet.statistics_columns, None);
+        assert_eq!(
+            config.parquet.column_compression["content"],
+            Compression::ZSTD(ZstdLevel::try_new(19).unwrap())
+        );
+        assert_eq!(
+            config.parquet.columns().collect::<Vec<_>>(),
+            ["hash", "content"]
+        );
+
+        assert!(toml::from_str::<ConfigFile>("unknown = 1").is_err());
+        assert!(toml::from_str::<ConfigFile>("compression = \"zstd(99)\"").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::channel::ConversionCounter;
+use crate::progress::OutputCounter;
+use crate::{ConvertionOptions, OutputFormat};
+use arrow::ipc::reader::FileReader;
+use arrow::temporal_conversions::timestamp_ms_to_datetime;
+use parquet::errors::ParquetError;
+use parquet::file::reader::{FileReader as _, SerializedFileReader};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_PREFIX: &str = "archive_to_parquet.";
+/// Inputs are only listed when there are at most this many, otherwise only their hash is written
+const MAX_LISTED_INPUTS: usize = 1000;
+
+/// The counters of a conversion, as recorded in the metadata of an output file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceCounters {
+    pub total_batches: u64,
+    pub total_entries: u64,
+    pub total_entries_bytes: u64,
+    pub output_rows: u64,
+    pub output_bytes: u64,
+}
+
+impl From<&ConversionCounter> for ProvenanceCounters {
+    fn from(counter: &ConversionCounter) -> Self {
+        Self {
+            total_batches: counter.total_batches,
+            total_entries: counter.total_entries,
+            total_entries_bytes: counter.total_entries_bytes,
+            output_rows: counter.output_rows,
+            output_bytes: counter.output_bytes,
+        }
+    }
+}
+
+/// How an output file was produced. This is written to the key-value metadata of every Parquet
+/// and Arrow IPC file, and can be read back with [Provenance::read].
+///
+/// The counters and `finished_at` are recorded when the file is finished, so a file that is
+/// finished before the end of the conversion (e.g. when rolling over to a new file) has the
+/// counters at that point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// The version of archive-to-parquet
+    pub version: String,
+    /// The [ConvertionOptions] used, as displayed
+    pub options: String,
+    /// RFC 3339 timestamps of when the conversion started and when the file was finished
+    pub started_at: String,
+    pub finished_at: String,
+    pub input_count: u64,
+    /// The inputs, if there were no more than 1000 of them
+    pub inputs: Option<Vec<String>>,
+    /// The SHA256 of the inputs, each followed by a newline
+    pub inputs_sha256: String,
+    pub counters: ProvenanceCounters,
+}
+
+impl Provenance {
+    fn to_key_value_metadata(&self) -> Vec<(String, String)> {
+        let mut metadata = vec![
+            ("version", self.version.clone()),
+            ("options", self.options.clone()),
+            ("started_at", self.started_at.clone()),
+            ("finished_at", self.finished_at.clone()),
+            ("input_count", self.input_count.to_string()),
+            ("inputs_sha256", self.inputs_sha256.clone()),
+            (
+                "counters",
+                serde_json::to_string(&self.counters).expect("counters serialize"),
+            ),
+        ];
+        if let Some(inputs) = &self.inputs {
+            metadata.push((
+                "inputs",
+                serde_json::to_string(inputs).expect("inputs serialize"),
+            ));
+        }
+        metadata
+            .into_iter()
+            .map(|(key, value)| (format!("{KEY_PREFIX}{key}"), value))
+            .collect()
+    }
+
+    fn from_key_value_metadata(
+        metadata: &HashMap<String, String>,
+    ) -> parquet::errors::Result<Option<Self>> {
+        let get = |key: &str| metadata.get(&format!("{KEY_PREFIX}{key}"));
+        let Some(version) = get("version") else {
+            return Ok(None);
+        };
+        let required = |key: &str| {
+            get(key)
+                .cloned()
+                .ok_or_else(|| ParquetError::General(format!("Missing {KEY_PREFIX}{key} metadata")))
+        };
+        let invalid = |key: &str, e: &dyn std::fmt::Display| {
+            ParquetError::General(format!("Invalid {KEY_PREFIX}{key} metadata: {e}"))
+        };
+        let inputs = get("inputs")
+            .map(|inputs| serde_json::from_str(inputs).map_err(|e| invalid("inputs", &e)))
+            .transpose()?;
+        Ok(Some(Self {
+            version: version.clone(),
+            options: required("options")?,
+            started_at: required("started_at")?,
+            finished_at: required("finished_at")?,
+            input_count: required("input_count")?
+                .parse()
+                .map_err(|e| invalid("input_count", &e))?,
+            inputs,
+            inputs_sha256: required("inputs_sha256")?,
+            counters: serde_json::from_str(&required("counters")?)
+                .map_err(|e| invalid("counters", &e))?,
+        }))
+    }
+
+    /// Read the provenance of an output file written in the Parquet or Arrow IPC file format.
+    /// Returns `None` if the file has no provenance metadata, e.g. if it was written by an older
+    /// version. The Arrow IPC stream format has no footer, so contains no provenance.
+    pub fn read(path: &Path) -> parquet::errors::Result<Option<Self>> {
+        let file = File::open(path)?;
+        let metadata = match OutputFormat::detect(path)? {
+            OutputFormat::Ipc => FileReader::try_new(BufReader::new(file), None)?
+                .custom_metadata()
+                .clone(),
+            // The IPC stream format has no footer to store the provenance in
+            OutputFormat::IpcStream => return Ok(None),
+            OutputFormat::Parquet | OutputFormat::Delta => SerializedFileReader::new(file)?
+                .metadata()
+                .file_metadata()
+                .key_value_metadata()
+                .into_iter()
+                .flatten()
+                .filter_map(|kv| Some((kv.key.clone(), kv.value.clone()?)))
+                .collect(),
+        };
+        Self::from_key_value_metadata(&metadata)
+    }
+}
+
+/// Records the provenance of a running conversion, to be written to each file as it is finished.
+#[derive(Debug)]
+pub(crate) struct ProvenanceRecorder {
+    options: String,
+    started_at: String,
+    inputs: Vec<PathBuf>,
+    inputs_sha256: String,
+    counters: OutputCounter,
+}
+
+impl ProvenanceRecorder {
+    pub fn new(options: &ConvertionOptions, inputs: &[PathBuf], counters: OutputCounter) -> Self {
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        for input in inputs {
+            context.update(input.as_os_str().as_encoded_bytes());
+            context.update(b"\n");
+        }
+        let inputs_sha256 =
+            context
+                .finish()
+                .as_ref()
+                .iter()
+                .fold(String::new(), |mut hex, byte| {
+                    write!(hex, "{byte:02x}").unwrap();
+                    hex
+                });
+        Self {
+            options: options.to_string(),
+            started_at: now(),
+            inputs: inputs.to_vec(),
+            inputs_sha256,
+            counters,
+        }
+    }
+
+    /// The key-value metadata to write to a file that is being finished
+    pub fn key_value_metadata(&self) -> Vec<(String, String)> {
+        let counter = ConversionCounter::from(self.counters.clone());
+        Provenance {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options: self.options.clone(),
+            started_at: self.started_at.clone(),
+            finished_at: now(),
+            input_count: self.inputs.len() as u64,
+            inputs: (self.inputs.len() <= MAX_LISTED_INPUTS).then(|| {
+                self.inputs
+                    .iter()
+                    .map(|input| input.to_string_lossy().into_owned())
+                    .collect()
+            }),
+            inputs_sha256: self.inputs_sha256.clone(),
+            counters: ProvenanceCounters::from(&counter),
+        }
+        .to_key_value_metadata()
+    }
+}
+
+fn now() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    timestamp_ms_to_datetime(millis)
+        .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ConvertionOptions;
+    use crate::output::{OutputFormat, Table};
+    use crate::progress::OutputCounter;
+    use crate::provenance::{Provenance, ProvenanceRecorder};
+    use crate::{Output, ParquetSink};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_read_provenance() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let inputs = vec![PathBuf::from("a.tar"), PathBuf::from("b.zip")];
+        for format in [OutputFormat::Parquet, OutputFormat::Ipc] {
+            let mut options = ConvertionOptions::const_default();
+            options.output_format = format;
+            let output = Output::new(dir.join(format!("output.{}", format.extension())));
+            let recorder = ProvenanceRecorder::new(&options, &inputs, OutputCounter::default());
+            let sink = ParquetSink::new(&output, options.clone())
+                .unwrap()
+                .with_provenance(Arc::new(recorder));
+            let files = sink.finish().unwrap();
+            assert_eq!(files[0].table, Table::Files);
+
+            let provenance = Provenance::read(&files[0].path).unwrap().unwrap();
+            assert_eq!(provenance.version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(provenance.options, options.to_string());
+            assert_eq!(provenance.input_count, 2);
+            assert_eq!(
+                provenance.inputs,
+                Some(vec!["a.tar".to_string(), "b.zip".to_string()])
+            );
+            assert_eq!(provenance.counters.output_rows, 0);
+            assert!(provenance.started_at <= provenance.finished_at);
+        }
+    }
+}
@@ -1,86 +1,486 @@
 use crate::ConvertionOptions;
 use crate::batch::arrow_schema;
+use crate::chunking::{Chunker, chunks_schema, file_chunks_schema};
+use crate::dedupe::SeenHashes;
+use crate::hash_index::{HashIndex, HashKind};
 use crate::hasher::HASH_SIZE;
-use arrow::array::{Array, AsArray, BooleanArray};
+use crate::incremental::SourceFingerprint;
+use crate::output::{
+    DeltaTable, Journal, Output, OutputFileStats, Table, TableWriter, remove_sources,
+};
+use crate::provenance::ProvenanceRecorder;
+use crate::secrets::{findings_batch, findings_schema};
+use arrow::array::{Array, AsArray, BooleanArray, BooleanBufferBuilder};
 use arrow::compute::filter_record_batch;
+use arrow::datatypes::{Schema, SchemaRef};
 use arrow::record_batch::RecordBatch;
 use parquet::arrow::ArrowWriter;
 use parquet::basic::Compression;
+use parquet::errors::ParquetError;
 use parquet::file::properties::{EnabledStatistics, WriterProperties, WriterVersion};
 use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
 use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum, strum::EnumString, strum::Display)]
-#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
-#[derive(Default)]
+/// Which entries to include in the output.
+#[derive(Debug, Clone, Eq, PartialEq, Default, strum::EnumIs)]
 pub enum IncludeType {
     #[default]
     All,
+    /// Entries with content that is text in any detected encoding, e.g. UTF-8, UTF-16 or Latin-1.
     Text,
+    /// Entries with content that is not text
     Binary,
+    /// Entries with a detected MIME type matching any of the patterns, e.g. `image/*` or
+    /// `application/pdf`.
+    Mime(Vec<String>),
+}
+
+impl IncludeType {
+    pub fn matches_mime(&self, mime_type: Option<&str>) -> bool {
+        let (IncludeType::Mime(patterns), Some(mime_type)) = (self, mime_type) else {
+            return false;
+        };
+        patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some("*") => true,
+                Some(prefix) => mime_type
+                    .split_once('/')
+                    .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(prefix)),
+                None => mime_type.eq_ignore_ascii_case(pattern),
+            })
+    }
+}
+
+impl FromStr for IncludeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "all" => Ok(IncludeType::All),
+            "text" => Ok(IncludeType::Text),
+            "binary" => Ok(IncludeType::Binary),
+            patterns => {
+                let patterns: Vec<_> = patterns.split(',').map(|p| p.trim().to_string()).collect();
+                match patterns.iter().find(|p| p.split_once('/').is_none()) {
+                    Some(invalid) => Err(format!(
+                        "invalid include type {invalid:?}: expected all, text, binary or a MIME type pattern"
+                    )),
+                    None => Ok(IncludeType::Mime(patterns)),
+                }
+            }
+        }
+    }
 }
 
+impl Display for IncludeType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncludeType::All => write!(f, "all"),
+            IncludeType::Text => write!(f, "text"),
+            IncludeType::Binary => write!(f, "binary"),
+            IncludeType::Mime(patterns) => write!(f, "{}", patterns.join(",")),
+        }
+    }
+}
+
+/// How the output is split into tables.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[derive(Default)]
+pub enum TableLayout {
+    /// A single table, containing the metadata and content of every file
+    #[default]
+    Single,
+    /// A `files` table containing the metadata of every file, and a `blobs` table
+    /// containing the content of each unique hash exactly once
+    Normalised,
+    /// A `files` table containing the metadata of every file. The content of each unique file
+    /// is split into chunks with content-defined chunking: a `chunks` table contains each unique
+    /// chunk exactly once, and a `file_chunks` table maps each unique hash to its chunks.
+    Chunked,
+}
+
+/// Create a Parquet writer for the single table layout with the default options and the given
+/// compression. Use [new_parquet_writer_with_options] to write any other table or options.
 pub fn new_parquet_writer<T: Write + Send>(
     writer: T,
     compression: Compression,
 ) -> parquet::errors::Result<ArrowWriter<T>> {
-    let schema = arrow_schema();
+    let mut options = ConvertionOptions::const_default();
+    options.compression = compression;
+    new_parquet_writer_with_options(writer, arrow_schema(&options), &options)
+}
+
+/// Create a Parquet writer for a table with the given schema, configured by the compression and
+/// Parquet options of `options`.
+pub fn new_parquet_writer_with_options<T: Write + Send>(
+    writer: T,
+    schema: SchemaRef,
+    options: &ConvertionOptions,
+) -> parquet::errors::Result<ArrowWriter<T>> {
+    let parquet = &options.parquet;
     let mut props = WriterProperties::builder()
-        .set_compression(compression)
+        .set_compression(options.compression)
         .set_writer_version(WriterVersion::PARQUET_2_0)
         .set_dictionary_enabled(false)
         .set_bloom_filter_enabled(false)
         .set_statistics_enabled(EnabledStatistics::None)
         .set_column_encoding("hash".into(), parquet::basic::Encoding::PLAIN)
-        .set_write_batch_size(1024)
-        .set_data_page_size_limit(1024 * 1024)
-        .set_data_page_row_count_limit(20_00)
-        .set_max_row_group_size(1024 * 1024);
+        .set_write_batch_size(parquet.write_batch_size.unwrap_or(1024))
+        .set_data_page_size_limit(parquet.data_page_size_limit.unwrap_or(1024 * 1024))
+        .set_data_page_row_count_limit(parquet.data_page_row_count_limit.unwrap_or(20_00))
+        .set_max_row_group_size(parquet.max_row_group_size.unwrap_or(1024 * 1024));
 
     const BLOOM_FILTER_FIELDS: &[&str] = &["source", "path", "hash"];
-    const STATISTICS_FIELDS: &[&str] = &["source", "path", "size", "hash"];
-    const DICTIONARY_FIELDS: &[&str] = &["source", "path"];
+    const STATISTICS_FIELDS: &[&str] = &["source", "path", "size", "hash", "mime_type"];
+    const DICTIONARY_FIELDS: &[&str] = &["source", "path", "mime_type"];
 
-    for field in BLOOM_FILTER_FIELDS {
-        props = props.set_column_bloom_filter_enabled((*field).into(), true);
+    let columns = |configured: &Option<Vec<String>>, default: &[&str]| -> Vec<String> {
+        match configured {
+            Some(columns) => columns.clone(),
+            None => default.iter().map(|c| c.to_string()).collect(),
+        }
+    };
+    for field in columns(&parquet.bloom_filter_columns, BLOOM_FILTER_FIELDS) {
+        props = props.set_column_bloom_filter_enabled(field.into(), true);
+    }
+    for field in columns(&parquet.statistics_columns, STATISTICS_FIELDS) {
+        props = props.set_column_statistics_enabled(field.into(), EnabledStatistics::Page);
     }
-    for field in STATISTICS_FIELDS {
-        props = props.set_column_statistics_enabled((*field).into(), EnabledStatistics::Page);
+    for field in columns(&parquet.dictionary_columns, DICTIONARY_FIELDS) {
+        props = props.set_column_dictionary_enabled(field.into(), true);
     }
-    for field in DICTIONARY_FIELDS {
-        props = props.set_column_dictionary_enabled((*field).into(), true);
+    for (field, compression) in &parquet.column_compression {
+        props = props.set_column_compression(field.as_str().into(), *compression);
     }
 
     ArrowWriter::try_new(writer, schema, Some(props.build()))
 }
 
-pub struct ParquetSink<'a, T: Write + Send> {
-    writer: &'a mut ArrowWriter<T>,
-    seen_hashes: Option<HashSet<[u8; HASH_SIZE]>>,
+/// Records the inputs that have been fully written to an output that is appended to, so that
+/// later runs know which inputs have been converted. The inputs are committed to a [Journal]
+/// when the output is finished, and periodically if checkpoints are enabled, or to the Delta
+/// table when it is committed.
+///
+/// Rows of inputs that are not finished when the output is finished, e.g. because the
+/// conversion was interrupted, are removed before the output is committed. Otherwise a later
+/// incremental run would skip those inputs, and resuming would duplicate their rows.
+struct Commits {
+    output: Output,
+    options: ConvertionOptions,
+    /// Not set when writing a Delta table, which records the inputs in its commit instead
+    journal: Option<Journal>,
+    checkpoint_interval: Option<Duration>,
+    last_checkpoint: Instant,
+    /// The inputs that have been finished since the last commit
+    finished_inputs: Vec<(PathBuf, SourceFingerprint)>,
+    /// The inputs with rows written since the last commit
+    written_sources: HashSet<String>,
+    /// The inputs with rows written that have not been finished, over the whole conversion
+    unfinished: HashSet<String>,
+}
+
+impl Commits {
+    /// Record the inputs that the rows of a batch were read from
+    fn record_sources(&mut self, batch: &RecordBatch) {
+        let Some(sources) = batch.column_by_name("source") else {
+            return;
+        };
+        for source in sources.as_string_view().iter().flatten() {
+            if !self.written_sources.contains(source) {
+                self.written_sources.insert(source.to_string());
+                self.unfinished.insert(source.to_string());
+            }
+        }
+    }
+
+    /// The inputs with rows written since the last commit that have not been finished
+    fn partial_inputs(&self) -> Vec<String> {
+        let finished: HashSet<_> = self
+            .finished_inputs
+            .iter()
+            .map(|(path, _)| path.to_string_lossy())
+            .collect();
+        let mut partial: Vec<_> = self
+            .written_sources
+            .iter()
+            .filter(|source| !finished.contains(source.as_str()))
+            .cloned()
+            .collect();
+        partial.sort();
+        partial
+    }
+
+    /// Remove the rows of unfinished inputs from the files that have been written, returning
+    /// the files that still have rows.
+    fn remove_unfinished_rows(
+        &self,
+        files: Vec<OutputFileStats>,
+    ) -> parquet::errors::Result<Vec<OutputFileStats>> {
+        warn!(
+            "Removing the rows of {} unfinished inputs from the output",
+            self.unfinished.len()
+        );
+        let mut kept = Vec::with_capacity(files.len());
+        for mut file in files {
+            // The files of a Delta table are moved into place when they are committed, they are
+            // only visible once the commit is written
+            Journal::complete(&file.path)?;
+            match remove_sources(&self.output, &file.path, &self.unfinished, &self.options)? {
+                Some(0) => continue,
+                Some(rows) => {
+                    file.rows = rows;
+                    file.bytes = std::fs::metadata(&file.path)?.len();
+                }
+                None => {}
+            }
+            kept.push(file);
+        }
+        if self.journal.is_some() {
+            // The journal still lists the unfinished inputs as partial, which is tidied up by
+            // recovering it now that their rows have been removed
+            Journal::recover(&self.output, &self.options)?;
+        }
+        Ok(kept)
+    }
+}
+
+/// The tables written with the [TableLayout::Chunked] layout
+struct ChunkTables {
+    chunker: Chunker,
+    chunks: TableWriter,
+    file_chunks: TableWriter,
+}
+
+pub struct ParquetSink {
+    files: TableWriter,
+    blobs: Option<TableWriter>,
+    chunks: Option<ChunkTables>,
+    /// The secrets found in each file, if scanning for secrets
+    findings: Option<TableWriter>,
+    seen_hashes: Option<SeenHashes>,
+    unique: bool,
+    /// True if duplicates were skipped, and their hashes recorded, before being sent to the sink
+    duplicates_skipped: bool,
+    /// Where to write the seen hashes once the sink is finished
+    hash_index: Option<PathBuf>,
+    delta: Option<DeltaTable>,
+    commits: Option<Commits>,
 }
 
-impl<'a, T: Write + Send> ParquetSink<'a, T> {
-    pub fn new(writer: &'a mut ArrowWriter<T>, options: ConvertionOptions) -> Self {
-        let seen_hashes = if options.unique {
-            Some(HashSet::new())
+impl ParquetSink {
+    pub fn new(output: &Output, options: ConvertionOptions) -> parquet::errors::Result<Self> {
+        let seen_hashes = SeenHashes::for_options(&options, HashKind::Files)?;
+        let seen_chunks = SeenHashes::for_options(&options, HashKind::Chunks)?;
+        Self::try_new(output, options, seen_hashes, seen_chunks, false)
+    }
+
+    /// Create a sink that shares the seen hashes with the visitors. If the visitors skip
+    /// duplicates then the batches the sink is sent are not deduplicated again.
+    pub(crate) fn with_seen_hashes(
+        output: &Output,
+        options: ConvertionOptions,
+        seen_hashes: Option<SeenHashes>,
+        seen_chunks: Option<SeenHashes>,
+    ) -> parquet::errors::Result<Self> {
+        let duplicates_skipped = options.skips_duplicates();
+        Self::try_new(
+            output,
+            options,
+            seen_hashes,
+            seen_chunks,
+            duplicates_skipped,
+        )
+    }
+
+    fn try_new(
+        output: &Output,
+        options: ConvertionOptions,
+        seen_hashes: Option<SeenHashes>,
+        seen_chunks: Option<SeenHashes>,
+        duplicates_skipped: bool,
+    ) -> parquet::errors::Result<Self> {
+        let schema = arrow_schema(&options);
+        if options.layout != TableLayout::Single && schema.index_of("content").is_err() {
+            return Err(ParquetError::General(format!(
+                "The {} layout requires the content column, so cannot be used with no_content or no_raw_content",
+                options.layout
+            )));
+        }
+        // Content is classified as it is read, which it is not without the content column
+        if options.classify_content && options.no_content {
+            return Err(ParquetError::General(
+                "classify_content cannot be used with no_content".to_string(),
+            ));
+        }
+        // Without the content column the content is only read to store its text
+        if options.no_raw_content && !options.text_column {
+            return Err(ParquetError::General(
+                "no_raw_content requires text_column, use no_content to omit all content"
+                    .to_string(),
+            ));
+        }
+        // The findings are split from the batches into their own table
+        let schema = Arc::new(schema.project(&Self::non_findings_columns(&schema))?);
+        let mut schemas = vec![schema.clone()];
+        if options.layout == TableLayout::Chunked {
+            schemas.extend([chunks_schema(), file_chunks_schema()]);
+        }
+        if options.scan_secrets {
+            schemas.push(findings_schema());
+        }
+        if let Some(column) = options.parquet.columns().find(|column| {
+            schemas
+                .iter()
+                .all(|schema| schema.index_of(column).is_err())
+        }) {
+            return Err(ParquetError::General(format!(
+                "Unknown column {column:?} in Parquet writer options"
+            )));
+        }
+        let delta = if options.output_format.is_delta() {
+            Some(DeltaTable::try_open(
+                output,
+                schema.clone(),
+                options.partition_by,
+            )?)
         } else {
             None
         };
-        Self {
-            writer,
+        let (files, blobs) = match options.layout {
+            TableLayout::Single => (
+                TableWriter::try_new(output, Table::Files, schema, &options)?,
+                None,
+            ),
+            TableLayout::Chunked => {
+                let files_schema = Arc::new(schema.project(&Self::files_columns(&schema))?);
+                (
+                    TableWriter::try_new(output, Table::Files, files_schema, &options)?,
+                    None,
+                )
+            }
+            TableLayout::Normalised => {
+                let files_schema = Arc::new(schema.project(&Self::files_columns(&schema))?);
+                let blobs_schema = Arc::new(schema.project(&Self::blobs_columns(&schema))?);
+                (
+                    TableWriter::try_new(output, Table::Files, files_schema, &options)?,
+                    Some(TableWriter::try_new(
+                        output,
+                        Table::Blobs,
+                        blobs_schema,
+                        &options,
+                    )?),
+                )
+            }
+        };
+        let commits = options.is_appending().then(|| Commits {
+            output: output.clone(),
+            options: options.clone(),
+            journal: delta.is_none().then(|| Journal::new(output)),
+            checkpoint_interval: options.checkpoint_interval.map(Duration::from_secs),
+            last_checkpoint: Instant::now(),
+            finished_inputs: vec![],
+            written_sources: HashSet::new(),
+            unfinished: HashSet::new(),
+        });
+        let chunks = match options.layout {
+            TableLayout::Chunked => Some(ChunkTables {
+                chunker: Chunker::new(
+                    options.chunk_size.as_u64(),
+                    match seen_chunks {
+                        Some(seen_chunks) => seen_chunks,
+                        None => SeenHashes::new(&options)?,
+                    },
+                ),
+                chunks: TableWriter::try_new(output, Table::Chunks, chunks_schema(), &options)?,
+                file_chunks: TableWriter::try_new(
+                    output,
+                    Table::FileChunks,
+                    file_chunks_schema(),
+                    &options,
+                )?,
+            }),
+            _ => None,
+        };
+        let findings = options
+            .scan_secrets
+            .then(|| TableWriter::try_new(output, Table::Findings, findings_schema(), &options))
+            .transpose()?;
+        Ok(Self {
+            files,
+            blobs,
+            chunks,
+            findings,
             seen_hashes,
+            unique: options.unique,
+            duplicates_skipped,
+            hash_index: options.hash_index.clone(),
+            delta,
+            commits,
+        })
+    }
+
+    /// Record the provenance of the conversion in the metadata of every file written
+    pub(crate) fn with_provenance(mut self, provenance: Arc<ProvenanceRecorder>) -> Self {
+        self.files.set_provenance(provenance.clone());
+        for table in self.other_tables() {
+            table.set_provenance(provenance.clone());
         }
+        self
     }
 
-    fn deduplicate_batch(
-        record_batch: RecordBatch,
-        seen_hashes: &mut HashSet<[u8; HASH_SIZE]>,
-    ) -> parquet::errors::Result<RecordBatch> {
+    /// Every table other than the files table
+    fn other_tables(&mut self) -> impl Iterator<Item = &mut TableWriter> {
+        let chunk_tables = self
+            .chunks
+            .iter_mut()
+            .flat_map(|chunks| [&mut chunks.chunks, &mut chunks.file_chunks]);
+        self.blobs
+            .iter_mut()
+            .chain(chunk_tables)
+            .chain(self.findings.iter_mut())
+    }
+
+    /// All columns except the findings
+    fn non_findings_columns(schema: &Schema) -> Vec<usize> {
+        (0..schema.fields().len())
+            .filter(|idx| schema.field(*idx).name() != "findings")
+            .collect()
+    }
+
+    /// All columns except the content
+    fn files_columns(schema: &Schema) -> Vec<usize> {
+        (0..schema.fields().len())
+            .filter(|idx| schema.field(*idx).name() != "content")
+            .collect()
+    }
+
+    /// The hash and content columns. The content column is checked to exist when the sink is
+    /// created.
+    fn blobs_columns(schema: &Schema) -> Vec<usize> {
+        ["hash", "content"]
+            .iter()
+            .map(|name| schema.index_of(name).expect("column not found"))
+            .collect()
+    }
+
+    /// Add the hashes in a batch to the seen hashes, returning a mask that is true for each
+    /// row with a hash that had not been seen before.
+    fn insert_hashes(
+        record_batch: &RecordBatch,
+        seen_hashes: &SeenHashes,
+    ) -> parquet::errors::Result<BooleanArray> {
         let hashes = record_batch
             .column_by_name("hash")
             .expect("hash column not found")
             .as_fixed_size_binary();
-        let mut unique_indexes = Vec::new();
         assert_eq!(
             hashes.value_length(),
             HASH_SIZE as i32,
@@ -88,36 +488,186 @@ impl<'a, T: Write + Send> ParquetSink<'a, T> {
         );
         assert!(!hashes.is_nullable(), "Hash column is nullable");
 
-        for (idx, hash) in hashes.iter().enumerate() {
-            let hash: [u8; HASH_SIZE] = hash.unwrap().try_into().unwrap();
-            if seen_hashes.insert(hash) {
-                unique_indexes.push(idx);
-            }
+        let mut unique = BooleanBufferBuilder::new(hashes.len());
+        for idx in 0..hashes.len() {
+            let hash: &[u8; HASH_SIZE] = hashes.value(idx).try_into().unwrap();
+            unique.append(seen_hashes.insert(hash)?);
         }
+        Ok(BooleanArray::new(unique.finish(), None))
+    }
 
-        let select_mask = BooleanArray::from_iter(
-            (0..record_batch.num_rows()).map(|idx| Some(unique_indexes.contains(&idx))),
-        );
-
+    fn deduplicate_batch(
+        record_batch: RecordBatch,
+        seen_hashes: &SeenHashes,
+    ) -> parquet::errors::Result<RecordBatch> {
+        let select_mask = Self::insert_hashes(&record_batch, seen_hashes)?;
         Ok(filter_record_batch(&record_batch, &select_mask)?)
     }
 
     pub fn write_batch(&mut self, batch: RecordBatch) -> parquet::errors::Result<WriteBatchOutput> {
-        let batch = match &mut self.seen_hashes {
+        let mut findings_bytes = 0;
+        let batch = match &mut self.findings {
+            Some(findings) => {
+                let findings_batch = findings_batch(&batch)?;
+                findings.write(&findings_batch)?;
+                findings_bytes = findings_batch.get_array_memory_size() as u64;
+                batch.project(&Self::non_findings_columns(&batch.schema()))?
+            }
             None => batch,
-            Some(seen_hashes) => Self::deduplicate_batch(batch, seen_hashes)?,
         };
+        if self.blobs.is_none() && self.chunks.is_none() {
+            let batch = match &self.seen_hashes {
+                _ if self.duplicates_skipped => batch,
+                Some(seen_hashes) if self.unique => Self::deduplicate_batch(batch, seen_hashes)?,
+                Some(seen_hashes) => {
+                    // Only recording the hashes for the hash index
+                    Self::insert_hashes(&batch, seen_hashes)?;
+                    batch
+                }
+                None => batch,
+            };
+            if let Some(commits) = &mut self.commits {
+                commits.record_sources(&batch);
+            }
+            self.files.write(&batch)?;
+            return Ok(WriteBatchOutput {
+                num_rows: batch.num_rows() as u64,
+                bytes: batch.get_array_memory_size() as u64 + findings_bytes,
+            });
+        }
 
-        let output = WriteBatchOutput {
-            num_rows: batch.num_rows() as u64,
-            bytes: batch.get_array_memory_size() as u64,
-        };
-        self.writer.write(&batch)?;
-        Ok(output)
+        // Every file is written to the files table, but only content with a hash that has not
+        // been seen before is written to the blobs table, or chunked.
+        let schema = batch.schema();
+        let files_batch = batch.project(&Self::files_columns(&schema))?;
+        let blobs_batch = batch.project(&Self::blobs_columns(&schema))?;
+        let blobs_batch = Self::deduplicate_batch(
+            blobs_batch,
+            self.seen_hashes.as_ref().expect("seen hashes not set"),
+        )?;
+        if let Some(commits) = &mut self.commits {
+            commits.record_sources(&files_batch);
+        }
+        self.files.write(&files_batch)?;
+        let mut bytes = files_batch.get_array_memory_size();
+        if let Some(blobs) = &mut self.blobs {
+            blobs.write(&blobs_batch)?;
+            bytes += blobs_batch.get_array_memory_size();
+        }
+        if let Some(chunks) = &mut self.chunks {
+            let chunked = chunks.chunker.chunk_batch(&blobs_batch)?;
+            chunks.chunks.write(&chunked.chunks)?;
+            chunks.file_chunks.write(&chunked.file_chunks)?;
+            bytes += chunked.chunks.get_array_memory_size()
+                + chunked.file_chunks.get_array_memory_size();
+        }
+        Ok(WriteBatchOutput {
+            num_rows: files_batch.num_rows() as u64,
+            bytes: bytes as u64 + findings_bytes,
+        })
     }
 
     pub fn flush(&mut self) -> parquet::errors::Result<()> {
-        self.writer.flush()
+        self.files.flush()?;
+        for table in self.other_tables() {
+            table.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Record that every row from an input has been written to the sink.
+    pub fn input_finished(&mut self, path: PathBuf, fingerprint: SourceFingerprint) {
+        if let Some(commits) = &mut self.commits {
+            commits.unfinished.remove(&*path.to_string_lossy());
+            commits.finished_inputs.push((path, fingerprint));
+        }
+    }
+
+    /// Checkpoint the output if checkpoints are enabled and the interval has elapsed.
+    pub fn maybe_checkpoint(&mut self) -> parquet::errors::Result<()> {
+        match &self.commits {
+            Some(Commits {
+                checkpoint_interval: Some(interval),
+                last_checkpoint,
+                ..
+            }) if last_checkpoint.elapsed() >= *interval => self.checkpoint(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finish every open file and record them in the journal, along with the inputs that have
+    /// been finished since the last checkpoint.
+    fn checkpoint(&mut self) -> parquet::errors::Result<()> {
+        let Some(commits) = &mut self.commits else {
+            return Ok(());
+        };
+        let Some(journal) = &commits.journal else {
+            return Ok(());
+        };
+        let mut files = self.files.checkpoint()?;
+        if let Some(blobs) = &mut self.blobs {
+            files.extend(blobs.checkpoint()?);
+        }
+        if let Some(chunks) = &mut self.chunks {
+            files.extend(chunks.chunks.checkpoint()?);
+            files.extend(chunks.file_chunks.checkpoint()?);
+        }
+        if let Some(findings) = &mut self.findings {
+            files.extend(findings.checkpoint()?);
+        }
+        commits.last_checkpoint = Instant::now();
+        if files.is_empty() && commits.finished_inputs.is_empty() {
+            return Ok(());
+        }
+        journal.checkpoint(&files, &commits.finished_inputs, &commits.partial_inputs())?;
+        info!(
+            "Checkpoint: {} files written, {} inputs completed",
+            files.len(),
+            commits.finished_inputs.len()
+        );
+        commits.finished_inputs.clear();
+        commits.written_sources.clear();
+        Ok(())
+    }
+
+    /// Finish writing all tables, returning statistics about every file written.
+    pub fn finish(mut self) -> parquet::errors::Result<Vec<OutputFileStats>> {
+        self.checkpoint()?;
+        let mut files = self.files.finish()?;
+        if let Some(blobs) = self.blobs {
+            files.extend(blobs.finish()?);
+        }
+        if let Some(chunks) = self.chunks {
+            files.extend(chunks.chunks.finish()?);
+            files.extend(chunks.file_chunks.finish()?);
+        }
+        if let Some(findings) = self.findings {
+            files.extend(findings.finish()?);
+        }
+        let mut removed_rows = false;
+        if let Some(commits) = self.commits.as_ref().filter(|c| !c.unfinished.is_empty()) {
+            files = commits.remove_unfinished_rows(files)?;
+            removed_rows = true;
+        }
+        if let (Some(path), Some(seen_hashes)) = (&self.hash_index, self.seen_hashes) {
+            if removed_rows {
+                // The seen hashes include those of the rows that were removed
+                warn!("Not writing the hash index to {path:?}, as some inputs were not finished");
+            } else {
+                HashIndex::write(path, &seen_hashes)?;
+            }
+        }
+        if let Some(delta) = self.delta {
+            let inputs = self
+                .commits
+                .map(|commits| commits.finished_inputs)
+                .unwrap_or_default();
+            match delta.commit(&files, &inputs)? {
+                Some(version) => info!("Committed version {version} of the Delta table"),
+                None => info!("No new files to commit to the Delta table"),
+            }
+        }
+        Ok(files)
     }
 }
 
@@ -129,9 +679,32 @@ pub struct WriteBatchOutput {
 
 #[cfg(test)]
 mod tests {
-    use crate::IncludeType;
+    use crate::batch::OutputBatch;
+    use crate::batch::arrow_schema;
+    use crate::incremental::{ExistingSources, SourceFingerprint};
+    use crate::output::{Output, read_file};
+    use crate::{
+        ConvertionOptions, FileEntry, IncludeType, OutputFormat, ParquetSink, TableLayout,
+        new_parquet_writer,
+    };
+    use arrow::array::AsArray;
+    use arrow::compute::cast;
+    use arrow::datatypes::DataType;
+    use std::path::{Path, PathBuf};
     use std::str::FromStr;
 
+    #[test]
+    fn test_new_parquet_writer() {
+        let writer = new_parquet_writer(vec![], parquet::basic::Compression::SNAPPY).unwrap();
+        let bytes = bytes::Bytes::from(writer.into_inner().unwrap());
+        let reader =
+            parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+        assert_eq!(
+            reader.schema().fields(),
+            arrow_schema(&ConvertionOptions::const_default()).fields()
+        );
+    }
+
     #[test]
     fn test_include_type() {
         let include_type = IncludeType::from_str("all").unwrap();
@@ -140,5 +713,98 @@ mod tests {
         assert_eq!(include_type, IncludeType::Text);
         let include_type = IncludeType::from_str("binary").unwrap();
         assert_eq!(include_type, IncludeType::Binary);
+        let include_type = IncludeType::from_str("image/*,application/pdf").unwrap();
+        assert_eq!(
+            include_type,
+            IncludeType::Mime(vec!["image/*".into(), "application/pdf".into()])
+        );
+        assert!(IncludeType::from_str("foo").is_err());
+    }
+
+    #[test]
+    fn test_include_type_matches_mime() {
+        let include_type = IncludeType::from_str("image/*,application/pdf").unwrap();
+        assert!(include_type.matches_mime(Some("image/png")));
+        assert!(include_type.matches_mime(Some("application/pdf")));
+        assert!(!include_type.matches_mime(Some("application/zip")));
+        assert!(!include_type.matches_mime(None));
+        let include_type = IncludeType::from_str("*/*").unwrap();
+        assert!(include_type.matches_mime(Some("font/woff2")));
+        assert!(!include_type.matches_mime(None));
+    }
+
+    #[test]
+    fn test_layout_requires_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("output");
+        let output = Output::new(dir.join("output.parquet"));
+        for layout in [TableLayout::Normalised, TableLayout::Chunked] {
+            let mut options = ConvertionOptions::const_default();
+            options.layout = layout;
+            options.no_content = true;
+            assert!(ParquetSink::new(&output, options.clone()).is_err());
+            options.no_content = false;
+            options.text_column = true;
+            options.no_raw_content = true;
+            assert!(ParquetSink::new(&output, options).is_err());
+        }
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_classify_requires_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = Output::new(dir.join("output.parquet"));
+        let mut options = ConvertionOptions::const_default();
+        options.classify_content = true;
+        options.no_content = true;
+        assert!(ParquetSink::new(&output, options.clone()).is_err());
+        options.no_content = false;
+        options.no_raw_content = true;
+        assert!(ParquetSink::new(&output, options.clone()).is_err());
+        options.text_column = true;
+        ParquetSink::new(&output, options).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_inputs_not_committed() {
+        for output_format in [OutputFormat::Parquet, OutputFormat::Delta] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let output = Output::new(dir);
+            let mut options = ConvertionOptions::const_default();
+            options.output_format = output_format;
+            options.incremental = true;
+
+            // The conversion is interrupted after a.tar is finished, part way through b.tar
+            let mut batch = OutputBatch::new_with_options(options.clone());
+            for (source, path) in [("a.tar", "1"), ("b.tar", "1"), ("a.tar", "2")] {
+                let mut entry = FileEntry::from_bytes(path, path.to_string()).unwrap();
+                batch
+                    .add_record(Path::new(source), PathBuf::new(), &mut entry)
+                    .unwrap();
+            }
+            let mut sink = ParquetSink::new(&output, options.clone()).unwrap();
+            sink.write_batch(batch.create_record_batch_and_reset().unwrap())
+                .unwrap();
+            sink.input_finished(PathBuf::from("a.tar"), SourceFingerprint::default());
+            let files = sink.finish().unwrap();
+            assert_eq!(files.iter().map(|file| file.rows).sum::<u64>(), 2);
+
+            // Only a.tar is committed, so b.tar is converted again by the next run
+            let existing = ExistingSources::read(&output, &options).unwrap();
+            assert!(existing.contains("a.tar", &SourceFingerprint::default()));
+            assert!(!existing.contains_source("b.tar"));
+            let mut sources = vec![];
+            for file in existing.files() {
+                let (_, reader) = read_file(file, Some(&["source"])).unwrap();
+                for batch in reader {
+                    let column = cast(batch.unwrap().column(0), &DataType::Utf8).unwrap();
+                    sources.extend(column.as_string::<i32>().iter().flatten().map(String::from));
+                }
+            }
+            assert_eq!(sources, vec!["a.tar", "a.tar"], "{output_format}");
+        }
     }
 }
@@ -2,41 +2,99 @@ use crate::ConvertionOptions;
 use crate::anyreader_walker::{AnyWalker, ArchiveStack, EntryDetails, FileEntry, FormatKind};
 use crate::batch::OutputBatch;
 use crate::channel::RecordBatchSender;
+use crate::dedupe::SeenHashes;
+use crate::incremental::SourceFingerprint;
+use crate::path_filter::PathFilter;
+use crate::predicate::{EntryMetadata, Predicate};
 use crate::progress::Counters;
+use byte_unit::Byte;
 use std::io::Read;
+use std::ops::Range;
 use std::path::PathBuf;
 use tracing::{debug, error, trace};
 
 #[derive(Debug)]
 pub struct Visitor {
     input_path: PathBuf,
+    fingerprint: SourceFingerprint,
     batch: OutputBatch,
     channel: RecordBatchSender,
     stack: ArchiveStack,
     counters: Counters,
+    size_range: Option<Range<Byte>>,
+    extract_strings: bool,
+    paths: PathFilter,
+    archives: PathFilter,
+    predicate: Option<Predicate>,
 }
 
 impl Visitor {
     pub(crate) fn new(
         path: impl Into<PathBuf>,
+        fingerprint: SourceFingerprint,
         channel: RecordBatchSender,
         options: ConvertionOptions,
     ) -> Self {
         Self {
             input_path: path.into(),
+            fingerprint,
             channel,
-            batch: OutputBatch::new_with_options(options),
+            batch: OutputBatch::new_with_options(options.clone())
+                .with_source_fingerprint(fingerprint),
             stack: ArchiveStack::default(),
             counters: Counters::default(),
+            size_range: options.get_size_range(),
+            extract_strings: options.extract_strings,
+            paths: options.paths.clone(),
+            archives: options.archives.clone(),
+            predicate: options.predicate.clone(),
         }
     }
 }
 
 impl Visitor {
+    /// Skip entries with content that any visitor sharing the seen hashes has already read
+    pub(crate) fn set_seen_hashes(&mut self, seen_hashes: SeenHashes) {
+        self.batch.set_seen_hashes(seen_hashes);
+    }
+
     pub fn counters(&self) -> &Counters {
         &self.counters
     }
 
+    pub fn input_path(&self) -> &PathBuf {
+        &self.input_path
+    }
+
+    /// The size of the content that will be output for an entry, if it is known before reading
+    fn known_size(&self, entry: &FileEntry<impl Read>) -> Option<u64> {
+        // Only the strings of executables are output, which are smaller than the entry
+        if self.extract_strings && entry.format() == FormatKind::Executable {
+            return None;
+        }
+        entry.content_size()
+    }
+
+    /// Returns true if the entry's size is known before reading it, and is outside of the size
+    /// range. Otherwise entries are filtered by size once they have been read.
+    fn outside_size_range(&self, entry: &FileEntry<impl Read>) -> bool {
+        let Some(size_range) = &self.size_range else {
+            return false;
+        };
+        self.known_size(entry)
+            .is_some_and(|size| !size_range.contains(&Byte::from(size)))
+    }
+
+    fn check_cancelled(&self) -> std::io::Result<()> {
+        if self.channel.is_cancelled() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Conversion cancelled",
+            ));
+        }
+        Ok(())
+    }
+
     fn send_batch(&mut self) -> std::io::Result<()> {
         let batch = self
             .batch
@@ -49,33 +107,90 @@ impl Visitor {
     }
 
     fn try_walk(&mut self, entry: FileEntry<impl Read>) -> std::io::Result<()> {
-        self.walk(entry)?;
+        self.check_cancelled()?;
+        let result = self.walk(entry);
+        // Send any rows that were read before the walk was cancelled
         if !self.batch.is_empty() {
             self.send_batch()?;
         }
+        result?;
+        self.channel
+            .send_input_finished(self.input_path.clone(), self.fingerprint)?;
         Ok(())
     }
 
     pub fn start_walking(&mut self, entry: FileEntry<impl Read>) {
         debug!("Starting to walk: {}", entry.details());
-        if let Err(e) = self.try_walk(entry) {
-            error!("Error while walking {:?}: {}", self.stack.nested_path(), e);
-            self.channel.send_batch(Err(e)).ok(); // Channel disconnected, ignore
+        match self.try_walk(entry) {
+            Ok(()) => {}
+            Err(e) if self.channel.is_cancelled() => {
+                debug!("Stopped walking {:?}: {}", self.input_path, e);
+            }
+            Err(e) => {
+                error!("Error while walking {:?}: {}", self.stack.nested_path(), e);
+                self.channel.send_batch(Err(e)).ok(); // Channel disconnected, ignore
+            }
         }
     }
 }
 
 impl AnyWalker for Visitor {
     fn visit_file_entry(&mut self, entry: &mut FileEntry<impl Read>) -> std::io::Result<()> {
+        self.check_cancelled()?;
         trace!(
             "Processing file: {}. Current source: {}",
             entry.details(),
             self.stack.nested_path().display()
         );
 
-        let entry_size = self
-            .batch
-            .add_record(&self.input_path, self.stack.nested_path(), entry);
+        if !self.paths.is_empty()
+            && !self
+                .paths
+                .matches(&self.stack.nested_path().join(entry.path()))
+        {
+            trace!("Skipping file excluded by path: {}", entry.details());
+            return Ok(());
+        }
+        if self.outside_size_range(entry) {
+            trace!(
+                "Skipping file outside of the size range: {}",
+                entry.details()
+            );
+            return Ok(());
+        }
+
+        let entry_size = match &self.predicate {
+            None => self
+                .batch
+                .add_record(&self.input_path, self.stack.nested_path(), entry)?,
+            Some(predicate) => {
+                let path = self.stack.nested_path().join(entry.path());
+                let metadata = EntryMetadata {
+                    path: &path,
+                    size: self.known_size(entry),
+                    format: entry.format(),
+                    mime_type: entry.mime_type(),
+                    depth: self.stack.current_depth(),
+                };
+                match predicate.evaluate(&metadata, None) {
+                    Some(false) => {
+                        trace!("Skipping file not matching the filter: {}", entry.details());
+                        return Ok(());
+                    }
+                    Some(true) => {
+                        self.batch
+                            .add_record(&self.input_path, self.stack.nested_path(), entry)?
+                    }
+                    // The filter depends on the content, so is evaluated once it has been read
+                    None => self.batch.add_filtered_record(
+                        &self.input_path,
+                        self.stack.nested_path(),
+                        entry,
+                        |content| predicate.evaluate(&metadata, Some(content)) == Some(true),
+                    )?,
+                }
+            }
+        };
 
         self.counters.read_entry(entry_size);
 
@@ -90,6 +205,7 @@ impl AnyWalker for Visitor {
         details: &EntryDetails,
         format: FormatKind,
     ) -> std::io::Result<bool> {
+        self.check_cancelled()?;
         // Detect quine zip files
         if format.is_zip() && Some(details) == self.stack.last_entry() {
             debug!(
@@ -98,6 +214,19 @@ impl AnyWalker for Visitor {
             );
             return Ok(false);
         }
+        // The input itself is always extracted, the filter only applies to nested archives
+        if !self.stack.is_empty()
+            && !self.archives.is_empty()
+            && !self
+                .archives
+                .matches(&self.stack.nested_path().join(&details.path))
+        {
+            debug!(
+                "Not extracting archive excluded by path: {details}. Current source: {:?}",
+                self.stack.nested_path()
+            );
+            return Ok(false);
+        }
         self.stack.push_details(details.clone());
         debug!(
             "Processing archive: {details} - {format}. Current source: {:?}",
@@ -121,3 +250,57 @@ impl AnyWalker for Visitor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::anyreader::test::tar_archive;
+    use crate::channel::RecordBatchResult;
+    use crate::incremental::SourceFingerprint;
+    use crate::{ConvertionOptions, FileEntry, PathFilter, Visitor, new_record_batch_channel};
+    use arrow::array::AsArray;
+
+    /// The nested path of every file output when walking an input with an archive filter
+    fn walk(archives: PathFilter) -> Vec<String> {
+        let input = tar_archive([
+            ("keep.tar", tar_archive([("a.txt", "a")])),
+            ("skip.tar", tar_archive([("b.txt", "b")])),
+        ]);
+        let mut options = ConvertionOptions::const_default();
+        options.archives = archives;
+        let channel = new_record_batch_channel(16);
+        let mut visitor = Visitor::new(
+            "input.tar",
+            SourceFingerprint::default(),
+            channel.sender.clone(),
+            options,
+        );
+        visitor.start_walking(FileEntry::from_bytes("input.tar", input).unwrap());
+
+        let mut paths = vec![];
+        for result in channel.receiver.inner.try_iter() {
+            match result {
+                RecordBatchResult::Batch(batch) => {
+                    let batch_paths = batch.column_by_name("path").unwrap().as_string_view();
+                    paths.extend(batch_paths.iter().flatten().map(String::from));
+                }
+                RecordBatchResult::InputFinished(..) => {}
+                RecordBatchResult::Errored(e) => panic!("{e}"),
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_archive_filter() {
+        // The input is extracted even though it does not match the filter
+        let include = PathFilter::new(vec!["keep.tar".into()], vec![]).unwrap();
+        assert_eq!(walk(include), vec!["keep.tar/a.txt", "skip.tar"]);
+        let exclude = PathFilter::new(vec![], vec!["*.tar".into()]).unwrap();
+        assert_eq!(walk(exclude), vec!["keep.tar", "skip.tar"]);
+        assert_eq!(
+            walk(PathFilter::default()),
+            vec!["keep.tar/a.txt", "skip.tar/b.txt"]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use archive_to_parquet::{
+    Converter, ConvertionOptions, StandardConverter, new_record_batch_channel,
+};
+use arrow::array::AsArray;
+use arrow::ipc::reader::{FileReader, StreamReader};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::io::{Cursor, Write};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Convert a single file, writing the output to stdout with the given extra arguments
+fn convert_to_stdout(args: &[&str]) -> Vec<u8> {
+    let mut input = tempfile::NamedTempFile::with_suffix(".txt").unwrap();
+    input.write_all(b"hello world").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_archive-to-parquet"))
+        .arg("-")
+        .arg(input.path())
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+#[test]
+fn test_stdout_ipc_stream() {
+    let stdout = convert_to_stdout(&[]);
+    let reader = StreamReader::try_new(Cursor::new(stdout), None).unwrap();
+    let batches: Vec<_> = reader.map(Result::unwrap).collect();
+    let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+    assert_eq!(rows, 1);
+    let content = batches[0].column_by_name("content").unwrap();
+    assert_eq!(content.as_binary::<i64>().value(0), b"hello world");
+}
+
+#[test]
+fn test_stdout_ipc_file() {
+    let stdout = convert_to_stdout(&["--format", "ipc"]);
+    let reader = FileReader::try_new(Cursor::new(stdout), None).unwrap();
+    let batches: Vec<_> = reader.map(Result::unwrap).collect();
+    let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+    assert_eq!(rows, 1);
+    let content = batches[0].column_by_name("content").unwrap();
+    assert_eq!(content.as_binary::<i64>().value(0), b"hello world");
+}
+
+/// A writer into a buffer that can be read once the writer has been given away
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_convert_to_writer() {
+    let options = ConvertionOptions::const_default();
+    let channel = new_record_batch_channel(options.batch_count);
+    let mut converter = StandardConverter::new(options);
+    converter
+        .add_readers([("input.txt", 11, Cursor::new(b"hello world"))], &channel)
+        .unwrap();
+    let buffer = SharedBuffer::default();
+    let counts = converter
+        .convert_to_writer(buffer.clone(), channel)
+        .unwrap();
+    assert_eq!(counts.output_rows, 1);
+
+    let output = bytes::Bytes::from(buffer.0.lock().unwrap().clone());
+    let reader = ParquetRecordBatchReaderBuilder::try_new(output)
+        .unwrap()
+        .build()
+        .unwrap();
+    let batches: Vec<_> = reader.map(Result::unwrap).collect();
+    let content = batches[0].column_by_name("content").unwrap();
+    assert_eq!(content.as_binary::<i64>().value(0), b"hello world");
+}